@@ -1,5 +1,7 @@
 // going postal
 
+use crate::parameters::ENVELOPE_TIME;
+
 pub trait Envelope {
     // *time* is the time since note on.
     // *on* is whether or not the note is on
@@ -12,7 +14,8 @@ pub struct ADSR {
     decay: f32,
     sustain: f32,
     release: f32,
-    max_alpha: f32,
+    // the release starts from wherever the envelope was when the note was turned off
+    release_level: f32,
 }
 
 impl ADSR {
@@ -22,9 +25,33 @@ impl ADSR {
             decay,
             sustain,
             release,
-            max_alpha: 1.0,
+            release_level: 0.0,
         }
     }
+
+    // from the normalized parameters, the times go through the envelope time range
+    pub fn from_parameters(attack: f32, decay: f32, sustain: f32, release: f32) -> Self {
+        ADSR::new(
+            ENVELOPE_TIME.value(attack),
+            ENVELOPE_TIME.value(decay),
+            sustain,
+            ENVELOPE_TIME.value(release),
+        )
+    }
+
+    // Change the times and the sustain level of a running envelope,
+    // without forgetting where it is.
+    pub fn set_parameters(&mut self, attack: f32, decay: f32, sustain: f32, release: f32) {
+        self.attack = ENVELOPE_TIME.value(attack);
+        self.decay = ENVELOPE_TIME.value(decay);
+        self.sustain = sustain;
+        self.release = ENVELOPE_TIME.value(release);
+    }
+
+    // start over for a new note
+    pub fn reset(&mut self) {
+        self.release_level = 0.0;
+    }
 }
 
 impl Envelope for ADSR {
@@ -34,21 +61,20 @@ impl Envelope for ADSR {
         if on {
             if time < self.attack {
                 alpha = time * (1.0 / self.attack);
-                self.max_alpha = alpha;
 
             } else if time < self.attack + self.decay {
                 alpha = 1.0 - (time - self.attack) * ((1.0 - self.sustain) / self.decay);
             } else {
                 alpha = self.sustain;
             }
+            self.release_level = alpha;
         } else {
             // if the key is released before the sustain level has been reached,
-            // we should release from the max_alpha, not from the sustain level.
-            let sustain = f32::min(self.max_alpha, self.sustain);
-
+            // we should release from where the attack or decay got to,
+            // not from the sustain level.
             let time_since_off = time - time_off;
             if time_since_off < self.release {
-                alpha = sustain - (time_since_off * (self.sustain / self.release))
+                alpha = self.release_level * (1.0 - time_since_off / self.release);
             }
         }
         alpha
@@ -1,62 +1,272 @@
-// Attempting to make a filter....
-//
-use realfft::RealFftPlanner;
+// Filters that run in the time domain and keep their state between blocks.
+
+use crate::parameters::FILTER_CUTOFF;
+use crate::plugin_state::Choice;
+
+use std::f32::consts::PI;
+
+// how long it takes the cutoff to (mostly) reach a new value
+const CUTOFF_SMOOTHING_TIME: f32 = 0.005;
+
+// keep the cutoff in a range where the filter is stable
+const MIN_CUTOFF: f32 = 20.0;
+const MAX_CUTOFF_RATIO: f32 = 0.49;
+
+// how far the cutoff parameter moves to multiply the frequency by *ratio*
+pub fn cutoff_offset(ratio: f32) -> f32 {
+    ratio.ln() / (FILTER_CUTOFF.max / FILTER_CUTOFF.min).ln()
+}
 
 pub trait Filter {
-    fn process(&self, buffer: &mut Vec<f32>);
+    // filter the next sample
+    fn process(&mut self, input: f32) -> f32;
 }
 
-pub struct SimpleFilter {
-    frequency: f32,
+#[derive(Clone, Copy, PartialEq)]
+pub enum FilterMode {
+    LowPass,
+    HighPass,
+    BandPass,
+    Notch,
+    Peak,
+}
+
+impl Choice for FilterMode {
+    const ALL: &'static [Self] = &[
+        FilterMode::LowPass,
+        FilterMode::HighPass,
+        FilterMode::BandPass,
+        FilterMode::Notch,
+        FilterMode::Peak,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            FilterMode::LowPass => "Low Pass",
+            FilterMode::HighPass => "High Pass",
+            FilterMode::BandPass => "Band Pass",
+            FilterMode::Notch => "Notch",
+            FilterMode::Peak => "Peak",
+        }
+    }
+}
+
+/*
+ * Resonant multi-mode filter
+ *
+ * This is a state variable filter using the trapezoidal integration
+ * described by Andrew Simper (Cytomic). Unlike the naive version it
+ * stays stable and in tune when the cutoff is modulated quickly.
+ * All of the modes are mixed from the same low, band and high outputs.
+ */
+pub struct StateVariableFilter {
     sample_rate: f32,
+    mode: FilterMode,
+    cutoff: f32,
+    target_cutoff: f32,
+    smoothing: f32,
+    // damping, 2.0 means no resonance
+    k: f32,
+    // the gain of the peak mode at the cutoff
+    peak_gain: f32,
+
+    // integrator states
+    ic1eq: f32,
+    ic2eq: f32,
 }
 
-impl SimpleFilter {
-    pub fn new(frequency_norm: f32, sample_rate: f32) -> Self {
-        // parameters are 0 < p < 1, so we need to scale this
-        // to frequencies. the max frequency is half of the
-        // sample rate.
-        let frequency = frequency_norm * (sample_rate / 2.);
-        SimpleFilter {
-            frequency,
-            // can't store frequency_per_bucket here
-            // because it depends on the buffer size.
+impl StateVariableFilter {
+    pub fn new(mode: FilterMode, cutoff_norm: f32, resonance: f32, sample_rate: f32) -> Self {
+        let mut filter = StateVariableFilter {
             sample_rate,
+            mode,
+            cutoff: 0.0,
+            target_cutoff: 0.0,
+            smoothing: (-1.0 / (CUTOFF_SMOOTHING_TIME * sample_rate)).exp(),
+            k: 2.0,
+            peak_gain: 1.0,
+            ic1eq: 0.0,
+            ic2eq: 0.0,
+        };
+        filter.set_cutoff(cutoff_norm);
+        filter.set_resonance(resonance);
+
+        // don't sweep in from zero when the filter is created
+        filter.cutoff = filter.target_cutoff;
+        filter
+    }
+
+    // The cutoff is a position in the range of the cutoff parameter.
+    // Modulation can push it past either end, the frequency just
+    // keeps going up or down by octaves until it is clamped.
+    pub fn set_cutoff(&mut self, cutoff_norm: f32) {
+        let frequency = FILTER_CUTOFF.value(cutoff_norm);
+        self.target_cutoff = frequency
+            .max(MIN_CUTOFF)
+            .min(self.sample_rate * MAX_CUTOFF_RATIO);
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        // the cutoff stays at the same frequency, unless it doesn't fit anymore
+        let max_cutoff = sample_rate * MAX_CUTOFF_RATIO;
+        self.target_cutoff = self.target_cutoff.min(max_cutoff);
+        self.cutoff = self.cutoff.min(max_cutoff);
+        self.smoothing = (-1.0 / (CUTOFF_SMOOTHING_TIME * sample_rate)).exp();
+        self.sample_rate = sample_rate;
+    }
+
+    pub fn set_resonance(&mut self, resonance: f32) {
+        // a resonance of 1.0 gets close to self oscillation, but not quite
+        self.k = 2.0 * (1.0 - 0.98 * resonance.max(0.0).min(1.0));
+    }
+
+    // the peak mode boosts (or cuts) the band around the cutoff by *gain*,
+    // the resonance makes the band narrower
+    pub fn set_peak_gain(&mut self, gain: f32) {
+        self.peak_gain = gain;
+    }
+
+    pub fn set_mode(&mut self, mode: FilterMode) {
+        self.mode = mode;
+    }
+
+    // forget whatever the filter was ringing with
+    pub fn reset(&mut self) {
+        self.ic1eq = 0.0;
+        self.ic2eq = 0.0;
+    }
+
+    // Gain of the filter at *frequency* (in Hz) for the target cutoff.
+    // Because of the trapezoidal integration the digital response is the
+    // analog one with the frequency axis warped by tan().
+    pub fn magnitude_response(&self, frequency: f32) -> f32 {
+        let warp =
+            |f: f32| (PI * f.min(self.sample_rate * MAX_CUTOFF_RATIO) / self.sample_rate).tan();
+        let w = warp(frequency) / warp(self.target_cutoff);
+        let w2 = w * w;
+
+        let denominator = ((1.0 - w2) * (1.0 - w2) + (self.k * w) * (self.k * w)).sqrt();
+        let numerator = match self.mode {
+            FilterMode::LowPass => 1.0,
+            FilterMode::HighPass => w2,
+            FilterMode::BandPass => w,
+            FilterMode::Notch => (1.0 - w2).abs(),
+            FilterMode::Peak => {
+                let band = self.peak_gain * self.k * w;
+                ((1.0 - w2) * (1.0 - w2) + band * band).sqrt()
+            }
+        };
+        numerator / denominator
+    }
+}
+
+impl Filter for StateVariableFilter {
+    fn process(&mut self, input: f32) -> f32 {
+        // move the cutoff towards the target a little every sample
+        // so that automation doesn't cause zipper noise.
+        self.cutoff = self.target_cutoff + self.smoothing * (self.cutoff - self.target_cutoff);
+
+        let g = (PI * self.cutoff / self.sample_rate).tan();
+        let a1 = 1.0 / (1.0 + g * (g + self.k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+
+        let v3 = input - self.ic2eq;
+        let v1 = a1 * self.ic1eq + a2 * v3;
+        let v2 = self.ic2eq + a2 * self.ic1eq + a3 * v3;
+
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+
+        let low = v2;
+        let band = v1;
+        let high = input - self.k * v1 - v2;
+
+        match self.mode {
+            FilterMode::LowPass => low,
+            FilterMode::HighPass => high,
+            FilterMode::BandPass => band,
+            FilterMode::Notch => low + high,
+            // k * band is a band pass with a gain of 1 at the cutoff
+            FilterMode::Peak => input + (self.peak_gain - 1.0) * self.k * band,
         }
     }
 }
 
-impl Filter for SimpleFilter {
-    fn process(&self, buffer: &mut Vec<f32>) {
-        let buffer_size = buffer.len();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let mut planner = RealFftPlanner::<f32>::new();
-        let fft = planner.plan_fft_forward(buffer_size);
+    // how much of a sine at *frequency* makes it through the filter
+    fn gain(filter: &mut StateVariableFilter, frequency: f32, sample_rate: f32) -> f32 {
+        let samples = sample_rate as usize / 10;
+        let sine = |i: usize| (2.0 * PI * frequency * i as f32 / sample_rate).sin();
+        let rms = |sum: f32| (sum / samples as f32).sqrt();
 
-        let mut spectrum = fft.make_output_vec();
-        let spectrum_size = spectrum.len();
+        // let the filter settle first
+        for i in 0..samples {
+            filter.process(sine(i));
+        }
+        let (mut input, mut output) = (0.0, 0.0);
+        for i in samples..2 * samples {
+            input += sine(i) * sine(i);
+            output += filter.process(sine(i)).powi(2);
+        }
+        rms(output) / rms(input)
+    }
 
-        // process forwards
-        fft.process(buffer, &mut spectrum).unwrap();
+    #[test]
+    fn cutoff_is_the_same_at_every_sample_rate() {
+        let cutoff = FILTER_CUTOFF.normalize(1000.0);
+        for sample_rate in [44100.0, 48000.0, 96000.0] {
+            // the sample rate changes after the filter was made, like it does in a voice
+            let mut filter = StateVariableFilter::new(FilterMode::LowPass, cutoff, 0.0, 48000.0);
+            filter.set_sample_rate(sample_rate);
 
-        // find cutoff bucket
-        let frequency_per_bucket = (self.sample_rate / 2.0) / (spectrum_size as f32);
-        let cutoff_bucket_index = (self.frequency / frequency_per_bucket) as usize;
+            // without resonance the filter lets through half at the cutoff
+            let at_cutoff = gain(&mut filter, 1000.0, sample_rate);
+            assert!(
+                (at_cutoff - 0.5).abs() < 0.01,
+                "{} at {} Hz",
+                at_cutoff,
+                sample_rate
+            );
 
-        // because this is a dumb filter, just zero out all the buckets over the cutoff
-        for i in 0..spectrum_size {
-            // we need to normalize here or the amplitudes of our output
-            // samples will be wrong
-            spectrum[i] = spectrum[i].unscale(buffer_size as f32);
+            // and falls off by about 12 dB per octave above it
+            let octaves_up = gain(&mut filter, 4000.0, sample_rate);
+            assert!(
+                (octaves_up - 1.0 / 17.0).abs() < 0.005,
+                "{} at {} Hz",
+                octaves_up,
+                sample_rate
+            );
+        }
+    }
 
-            if i >= cutoff_bucket_index {
-                // TODO: come up with some other scale factor
-                // it is pretty tricky to get this to work tho.
-                spectrum[i] = spectrum[i].scale(0.0);
-            }
+    #[test]
+    fn peak_boosts_the_cutoff_and_nothing_else() {
+        let cutoff = FILTER_CUTOFF.normalize(1000.0);
+        let mut filter = StateVariableFilter::new(FilterMode::Peak, cutoff, 0.5, 48000.0);
+        filter.set_peak_gain(4.0);
+        for (frequency, expected) in [(1000.0, 4.0), (50.0, 1.0), (15000.0, 1.0)] {
+            let response = filter.magnitude_response(frequency);
+            let measured = gain(&mut filter, frequency, 48000.0);
+            assert!(
+                (response - expected).abs() < 0.05 * expected,
+                "{} at {} Hz",
+                response,
+                frequency
+            );
+            assert!(
+                (measured - response).abs() < 0.02 * response,
+                "{} at {} Hz",
+                measured,
+                frequency
+            );
         }
 
-        let ffti = planner.plan_fft_inverse(buffer_size);
-        ffti.process(&mut spectrum, buffer).unwrap();
+        // and the same gain below 1 cuts it
+        filter.set_peak_gain(0.25);
+        assert!((gain(&mut filter, 1000.0, 48000.0) - 0.25).abs() < 0.01);
     }
 }
@@ -0,0 +1,296 @@
+// Low frequency oscillators for modulation
+//
+// Every voice runs its own lfos, so they can restart with each note.
+// Free running lfos are copied into the voices at the start of a block
+// so that all of the voices move together.
+
+use crate::dsp::voice::MAX_WARP_DEPTH;
+use crate::parameters::{
+    LfoParameters, BIPOLAR_AMOUNT, LFO_FADE_IN, LFO_PHASE, LFO_PITCH, LFO_RATE,
+};
+use crate::plugin_state::Choice;
+
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// Every lfo starts sample and hold from a seed of its own,
+// otherwise all of the voices in a chord would hold the same values.
+static NEXT_SEED: AtomicU32 = AtomicU32::new(0x1234_5678);
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LfoShape {
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+    SampleAndHold,
+}
+
+impl Choice for LfoShape {
+    const ALL: &'static [Self] = &[
+        LfoShape::Sine,
+        LfoShape::Triangle,
+        LfoShape::Saw,
+        LfoShape::Square,
+        LfoShape::SampleAndHold,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            LfoShape::Sine => "Sine",
+            LfoShape::Triangle => "Triangle",
+            LfoShape::Saw => "Saw",
+            LfoShape::Square => "Square",
+            LfoShape::SampleAndHold => "Sample & Hold",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LfoMode {
+    // start from the phase offset with every note
+    Retrigger,
+    // keep going no matter what the notes do
+    FreeRunning,
+}
+
+impl Choice for LfoMode {
+    const ALL: &'static [Self] = &[LfoMode::Retrigger, LfoMode::FreeRunning];
+
+    fn name(self) -> &'static str {
+        match self {
+            LfoMode::Retrigger => "Retrigger",
+            LfoMode::FreeRunning => "Free Running",
+        }
+    }
+}
+
+// The length of one cycle when the lfo is synced to the tempo
+#[derive(Clone, Copy, PartialEq)]
+pub enum LfoSync {
+    // the rate is set in Hz
+    Off,
+    Whole,
+    Half,
+    Quarter,
+    Eighth,
+    Sixteenth,
+    ThirtySecond,
+    DottedHalf,
+    DottedQuarter,
+    DottedEighth,
+    TripletHalf,
+    TripletQuarter,
+    TripletEighth,
+    TripletSixteenth,
+}
+
+impl LfoSync {
+    // how many quarter notes one cycle lasts
+    pub fn beats(self) -> Option<f64> {
+        let beats = match self {
+            LfoSync::Off => return None,
+            LfoSync::Whole => 4.0,
+            LfoSync::Half => 2.0,
+            LfoSync::Quarter => 1.0,
+            LfoSync::Eighth => 0.5,
+            LfoSync::Sixteenth => 0.25,
+            LfoSync::ThirtySecond => 0.125,
+            LfoSync::DottedHalf => 3.0,
+            LfoSync::DottedQuarter => 1.5,
+            LfoSync::DottedEighth => 0.75,
+            LfoSync::TripletHalf => 4.0 / 3.0,
+            LfoSync::TripletQuarter => 2.0 / 3.0,
+            LfoSync::TripletEighth => 1.0 / 3.0,
+            LfoSync::TripletSixteenth => 1.0 / 6.0,
+        };
+        Some(beats)
+    }
+}
+
+impl Choice for LfoSync {
+    const ALL: &'static [Self] = &[
+        LfoSync::Off,
+        LfoSync::Whole,
+        LfoSync::Half,
+        LfoSync::Quarter,
+        LfoSync::Eighth,
+        LfoSync::Sixteenth,
+        LfoSync::ThirtySecond,
+        LfoSync::DottedHalf,
+        LfoSync::DottedQuarter,
+        LfoSync::DottedEighth,
+        LfoSync::TripletHalf,
+        LfoSync::TripletQuarter,
+        LfoSync::TripletEighth,
+        LfoSync::TripletSixteenth,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            LfoSync::Off => "Off",
+            LfoSync::Whole => "1/1",
+            LfoSync::Half => "1/2",
+            LfoSync::Quarter => "1/4",
+            LfoSync::Eighth => "1/8",
+            LfoSync::Sixteenth => "1/16",
+            LfoSync::ThirtySecond => "1/32",
+            LfoSync::DottedHalf => "1/2 Dotted",
+            LfoSync::DottedQuarter => "1/4 Dotted",
+            LfoSync::DottedEighth => "1/8 Dotted",
+            LfoSync::TripletHalf => "1/2 Triplet",
+            LfoSync::TripletQuarter => "1/4 Triplet",
+            LfoSync::TripletEighth => "1/8 Triplet",
+            LfoSync::TripletSixteenth => "1/16 Triplet",
+        }
+    }
+}
+
+// the frequency of the lfo in Hz, at the given tempo
+pub fn lfo_frequency(params: &LfoParameters, tempo: f64) -> f64 {
+    match LfoSync::from_parameter(params.sync.get()).beats() {
+        Some(beats) => tempo / 60.0 / beats,
+        None => f64::from(LFO_RATE.value(params.rate.get())),
+    }
+}
+
+#[derive(Clone)]
+pub struct Lfo {
+    // position within one cycle, 0 <= phase < 1
+    phase: f64,
+    // the value of the sample and hold shape for this cycle
+    held: f32,
+    // state of the random number generator for sample and hold
+    seed: u32,
+}
+
+impl Lfo {
+    pub fn new() -> Self {
+        // the seeds are spread out, and xorshift gets stuck on zero
+        let seed = NEXT_SEED.fetch_add(0x9e37_79b9, Ordering::Relaxed) | 1;
+        let mut lfo = Lfo {
+            phase: 0.0,
+            held: 0.0,
+            seed,
+        };
+        lfo.hold_next();
+        lfo
+    }
+
+    pub fn reset(&mut self) {
+        self.phase = 0.0;
+        self.hold_next();
+    }
+
+    // jump to a phase, e.g. to follow the host's transport
+    pub fn set_phase(&mut self, phase: f64) {
+        if phase.fract() < self.phase {
+            self.hold_next();
+        }
+        self.phase = phase.fract();
+    }
+
+    // The output for the next sample, faded in over the
+    // first seconds after note on. *time* is the time since note on,
+    // *rate* scales the rate of the settings.
+    pub fn process(&mut self, settings: &LfoSettings, time: f32, rate: f64) -> f32 {
+        let fade = if settings.fade_in > 0.0 {
+            (time / settings.fade_in).min(1.0)
+        } else {
+            1.0
+        };
+        let value = self.value(settings.shape, settings.phase_offset) * fade;
+        self.advance(settings.increment * rate);
+        value
+    }
+
+    // move forward by *increment* cycles
+    pub fn advance(&mut self, increment: f64) {
+        self.phase += increment;
+        if self.phase >= 1.0 {
+            self.phase = self.phase.fract();
+            self.hold_next();
+        }
+    }
+
+    // the output of the lfo, from -1.0 to 1.0
+    fn value(&self, shape: LfoShape, phase_offset: f32) -> f32 {
+        let phase = (self.phase as f32 + phase_offset).fract();
+        match shape {
+            LfoShape::Sine => (2.0 * PI * phase).sin(),
+            LfoShape::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            LfoShape::Saw => 2.0 * phase - 1.0,
+            LfoShape::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            LfoShape::SampleAndHold => self.held,
+        }
+    }
+
+    // xorshift is plenty random for an lfo
+    fn hold_next(&mut self) {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        self.held = self.seed as f32 / u32::MAX as f32 * 2.0 - 1.0;
+    }
+}
+
+// The settings of an lfo, read once per block
+pub struct LfoSettings {
+    pub mode: LfoMode,
+    shape: LfoShape,
+    phase_offset: f32,
+    // in seconds
+    fade_in: f32,
+    // how far the lfo moves every sample
+    pub increment: f64,
+
+    // how much one unit of lfo output moves each destination
+    pub warp: f32,
+    pub cutoff: f32,
+    // in semitones
+    pub pitch: f32,
+    pub amp: f32,
+}
+
+impl LfoSettings {
+    pub fn new(params: &LfoParameters, tempo: f64, sample_rate: f32) -> Self {
+        LfoSettings {
+            mode: LfoMode::from_parameter(params.mode.get()),
+            shape: LfoShape::from_parameter(params.shape.get()),
+            phase_offset: LFO_PHASE.value(params.phase.get()) / 360.0,
+            fade_in: LFO_FADE_IN.value(params.fade_in.get()),
+            increment: lfo_frequency(params, tempo) / f64::from(sample_rate),
+            warp: BIPOLAR_AMOUNT.value(params.warp_depth.get()) * MAX_WARP_DEPTH,
+            cutoff: BIPOLAR_AMOUNT.value(params.cutoff_depth.get()),
+            pitch: LFO_PITCH.value(params.pitch_depth.get()),
+            amp: params.amp_depth.get(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_lfo_holds_different_values() {
+        let mut lfos: Vec<Lfo> = (0..8).map(|_| Lfo::new()).collect();
+        for _ in 0..4 {
+            for (i, a) in lfos.iter().enumerate() {
+                for b in lfos.iter().skip(i + 1) {
+                    assert_ne!(a.held, b.held);
+                }
+            }
+            // a retriggered lfo keeps going with its own values
+            for lfo in lfos.iter_mut() {
+                lfo.reset();
+            }
+        }
+    }
+}
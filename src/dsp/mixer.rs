@@ -3,42 +3,43 @@
 // split into multiple files if we ever get more than two
 
 pub trait Mixer {
-    fn add_input(&mut self, channel: Vec<f32>, gain: f32);
-    fn process(&self) -> Vec<f32>;
+    // start mixing a new block of *buffer_size* samples
+    fn reset(&mut self, buffer_size: usize);
+    fn add_input(&mut self, channel: &[f32], gain: f32);
+    fn process(&mut self) -> &mut Vec<f32>;
 }
 
+// The output buffer is reused from block to block,
+// so mixing doesn't allocate once it has grown to the block size.
 pub struct SimpleMixer {
-    channels: Vec<Vec<f32>>,
-    gains: Vec<f32>,
+    output: Vec<f32>,
 }
 
 impl SimpleMixer {
     pub fn new() -> Self {
-        SimpleMixer {
-            channels: Vec::new(),
-            gains: Vec::new(),
-        }
+        SimpleMixer { output: Vec::new() }
+    }
+
+    // make room for blocks of up to *buffer_size* samples ahead of time
+    pub fn reserve(&mut self, buffer_size: usize) {
+        self.output.reserve(buffer_size);
     }
 }
 
 impl Mixer for SimpleMixer {
-    fn add_input(&mut self, channel: Vec<f32>, gain: f32) {
-        self.channels.push(channel);
-        self.gains.push(gain);
+    fn reset(&mut self, buffer_size: usize) {
+        self.output.clear();
+        self.output.resize(buffer_size, 0.0);
     }
 
-    fn process(&self) -> Vec<f32> {
-        let buffer_size = self.channels[0].len();
-
-        let mut output = vec![0.0; buffer_size];
-
-        for (i, channel) in self.channels.iter().enumerate() {
-            for j in 0..buffer_size {
-                output[j] += channel[j] * self.gains[i];
-            }
+    fn add_input(&mut self, channel: &[f32], gain: f32) {
+        for (output, input) in self.output.iter_mut().zip(channel) {
+            *output += input * gain;
         }
+    }
 
-        output
+    fn process(&mut self) -> &mut Vec<f32> {
+        &mut self.output
     }
 }
 
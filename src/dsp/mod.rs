@@ -1,74 +1,239 @@
 // module for beep boop
 
-use crate::plugin_state::PluginState;
+use crate::midi::{
+    MidiMessage, MidiMode, ALL_NOTES_OFF, ALL_SOUND_OFF, MPE_MANAGER_CHANNEL,
+    RESET_ALL_CONTROLLERS, SLIDE,
+};
+use crate::notes::NotePriority;
+use crate::plugin_state::{Choice, PluginState, NUM_LFOS};
 
-mod voice;
-use voice::Voice;
+pub mod voice;
+use voice::{PlayMode, StealPolicy, VoicePool};
 
 mod mixer;
 use mixer::{Amp, Mixer, SimpleAmp, SimpleMixer};
 
-mod filter;
-use filter::{Filter, SimpleFilter};
-
 use std::sync::Arc;
-use vst::buffer::AudioBuffer;
+use vst::api::TimeInfo;
+use vst::buffer::{AudioBuffer, Outputs};
+use vst::event::MidiEvent;
 
 pub mod envelope;
+pub mod filter;
+pub mod lfo;
+pub mod mod_matrix;
+use lfo::{Lfo, LfoSettings, LfoSync};
 pub mod oscillator;
 
+pub mod transport;
+use transport::Transport;
+
+// enough room for the events of a typical block, so queueing doesn't allocate
+const EVENT_QUEUE_SIZE: usize = 1024;
+
 pub(super) struct PluginDsp {
     params: Arc<PluginState>,
+    voices: VoicePool,
+    mixer: SimpleMixer,
+    // midi events for the next block, sorted by their offset into the block
+    events: Vec<MidiEvent>,
+    // the free running lfos are shared by all of the voices
+    free_lfos: [Lfo; NUM_LFOS],
+    // tempo and position of the host, as of the start of the next render
+    pub transport: Transport,
 }
 
 impl PluginDsp {
     pub fn new(params: Arc<PluginState>) -> Self {
-        Self { params }
+        let voices = VoicePool::new(params.clone());
+        Self {
+            params,
+            voices,
+            mixer: SimpleMixer::new(),
+            events: Vec::with_capacity(EVENT_QUEUE_SIZE),
+            free_lfos: [Lfo::new(), Lfo::new()],
+            transport: Transport::new(),
+        }
+    }
+
+    // Events are played at their delta frame in the next call to process.
+    pub fn queue_event(&mut self, event: MidiEvent) {
+        let index = self
+            .events
+            .iter()
+            .rposition(|queued| queued.delta_frames <= event.delta_frames)
+            .map_or(0, |index| index + 1);
+        self.events.insert(index, event);
     }
 
-    pub fn process(&mut self, buffer: &mut AudioBuffer<f32>) {
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.params.sample_rate.set(sample_rate);
+        self.voices.set_sample_rate(sample_rate);
+    }
+
+    // the buffers are grown here, so that process doesn't have to allocate
+    pub fn set_block_size(&mut self, block_size: usize) {
+        self.mixer.reserve(block_size);
+        self.voices.reserve(block_size);
+    }
+
+    // Drop everything that is playing, e.g. when the host suspends the plugin.
+    pub fn reset(&mut self) {
+        self.events.clear();
+        self.params.all_notes_off();
+        self.voices.silence();
+    }
+
+    pub fn process(&mut self, buffer: &mut AudioBuffer<f32>, time_info: Option<TimeInfo>) {
+        self.transport.update(time_info);
+
         let num_samples = buffer.samples();
-        let num_channels = buffer.output_count();
         let (_inputs, mut outputs) = buffer.split();
 
-        let mut mixer = SimpleMixer::new();
+        // split the block at every event so that notes start on the right sample
+        let mut start = 0;
+        let mut next_event = 0;
+        while start < num_samples {
+            while next_event < self.events.len() && self.event_frame(next_event) <= start {
+                self.handle_event(self.events[next_event]);
+                next_event += 1;
+            }
+
+            let end = if next_event < self.events.len() {
+                self.event_frame(next_event).min(num_samples)
+            } else {
+                num_samples
+            };
+
+            self.render(&mut outputs, start, end);
+            start = end;
+        }
 
-        let notes = self.params.notebook.read().unwrap().get_notes();
-        if notes.is_empty() {
+        // anything that didn't fit into this block
+        for i in next_event..self.events.len() {
+            self.handle_event(self.events[i]);
+        }
+        self.events.clear();
+    }
+
+    fn event_frame(&self, index: usize) -> usize {
+        self.events[index].delta_frames.max(0) as usize
+    }
+
+    fn handle_event(&mut self, event: MidiEvent) {
+        let message = match MidiMessage::from_event(&event) {
+            Some(message) => message,
+            None => return,
+        };
+
+        // mpe spreads the notes over all of the channels
+        let mpe = self.params.midi_mode() == MidiMode::Mpe;
+        if !mpe && !self.params.receive_channel().accepts(message.channel()) {
             return;
         }
 
-        for note in notes {
-            let voice = Voice::new(note, self.params.clone());
-            let voice_output = voice.play(num_samples);
-            mixer.add_input(voice_output, 1.0);
+        // outside of mpe mode the channel doesn't tell notes apart
+        let channel = if mpe { message.channel() } else { 0 };
+        // bend, pressure and slide on a member channel belong to the note on that channel
+        let member = mpe && channel != MPE_MANAGER_CHANNEL;
+
+        match message {
+            MidiMessage::NoteOn { note, velocity, .. } => {
+                self.params.note_on(channel, note, velocity)
+            }
+            MidiMessage::NoteOff { note, .. } => self.params.note_off(channel, note),
+            MidiMessage::PolyPressure { note, pressure, .. } => {
+                self.params.poly_pressure(channel, note, pressure)
+            }
+            // the channel mode messages apply to the whole synth
+            MidiMessage::ControlChange {
+                controller: ALL_SOUND_OFF,
+                ..
+            } => {
+                self.params.all_notes_off();
+                self.voices.silence();
+            }
+            MidiMessage::ControlChange {
+                controller: ALL_NOTES_OFF,
+                ..
+            } => self.params.all_notes_off(),
+            MidiMessage::ControlChange {
+                controller: RESET_ALL_CONTROLLERS,
+                ..
+            } => self.params.reset_controllers(),
+            MidiMessage::ControlChange {
+                controller: SLIDE,
+                value,
+                ..
+            } if member => self.params.mpe_slide(channel, value),
+            MidiMessage::ControlChange {
+                controller, value, ..
+            } => self.params.control_change(controller, value),
+            MidiMessage::ProgramChange { program, .. } => self.params.program_change(program),
+            MidiMessage::ChannelPressure { pressure, .. } if member => {
+                self.params.mpe_pressure(channel, pressure)
+            }
+            MidiMessage::ChannelPressure { pressure, .. } => {
+                self.params.set_channel_pressure(pressure)
+            }
+            MidiMessage::PitchBend { bend, .. } if member => self.params.mpe_bend(channel, bend),
+            MidiMessage::PitchBend { bend, .. } => self.params.pitch_bend.set(bend),
+        }
+    }
+
+    // render the samples from *start* up to *end* into the outputs
+    fn render(&mut self, outputs: &mut Outputs<f32>, start: usize, end: usize) {
+        let num_samples = end - start;
+
+        {
+            let notebook = self.params.notebook.read().unwrap();
+            let priority = NotePriority::from_parameter(self.params.global.note_priority.get());
+            match PlayMode::from_parameter(self.params.global.play_mode.get()) {
+                PlayMode::Poly => {
+                    let policy = StealPolicy::from_parameter(self.params.global.steal_policy.get());
+                    self.voices
+                        .update(&notebook, self.params.polyphony(), policy);
+                }
+                PlayMode::Mono => self.voices.update_mono(&notebook, priority, false),
+                PlayMode::Legato => self.voices.update_mono(&notebook, priority, true),
+            }
+        }
+
+        // synced lfos follow the host's position while it is playing
+        if self.transport.playing {
+            for (lfo, params) in self.free_lfos.iter_mut().zip(self.params.lfos.iter()) {
+                if let Some(beats) = LfoSync::from_parameter(params.sync.get()).beats() {
+                    lfo.set_phase(self.transport.phase(beats));
+                }
+            }
         }
-        let mut mixed_output = mixer.process();
 
-        let filter = SimpleFilter::new(
-            self.params.filter_cutoff.get(),
-            self.params.sample_rate.get(),
-        );
+        let tempo = self.transport.tempo;
+        self.mixer.reset(num_samples);
+        for voice in self.voices.active_voices_mut() {
+            self.mixer
+                .add_input(voice.play(num_samples, &self.free_lfos, tempo), 1.0);
+        }
 
-        filter.process(&mut mixed_output);
+        let sample_rate = self.params.sample_rate.get();
+        for (lfo, params) in self.free_lfos.iter_mut().zip(self.params.lfos.iter()) {
+            let settings = LfoSettings::new(params, tempo, sample_rate);
+            for _ in 0..num_samples {
+                lfo.advance(settings.increment);
+            }
+        }
+        let mixed_output = self.mixer.process();
 
-        let amp = SimpleAmp::new(self.params.main_volume.get());
-        amp.process(&mut mixed_output);
+        let amp = SimpleAmp::new(self.params.global.main_volume.get());
+        amp.process(mixed_output);
 
         // put the main output into the output channels
         // there is no support for true stereo synths
-        for i in 0..num_samples {
-            for n in 0..num_channels {
-                let channel = outputs.get_mut(n);
-                channel[i] = mixed_output[i];
-            }
+        for n in 0..outputs.len() {
+            let channel = outputs.get_mut(n);
+            channel[start..end].copy_from_slice(mixed_output);
         }
 
-        let block_time = num_samples as f32 / self.params.sample_rate.get();
-        let mut notebook = self.params.notebook.write().unwrap();
-        notebook.update_note_times(block_time);
-
-        // TODO: dynamically calculate this threshold based on envelope parameters
-        notebook.purge_old_notes(0.5);
+        self.transport.advance(num_samples, sample_rate);
     }
 }
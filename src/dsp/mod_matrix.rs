@@ -0,0 +1,205 @@
+// Modulation matrix
+//
+// Each slot takes a source, scales it by a bipolar amount and
+// (optionally) by a second "via" source, and adds it to a destination.
+// The slots are read once per block and evaluated for every sample.
+
+use crate::parameters::BIPOLAR_AMOUNT;
+use crate::plugin_state::{Choice, PluginState, NUM_LFOS, NUM_MOD_SLOTS};
+
+// how far a slot at full amount moves the destinations that aren't normalized
+pub const MAX_MOD_PITCH: f32 = 24.0;
+pub const MAX_MOD_LFO_RATE_OCTAVES: f32 = 4.0;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ModSource {
+    None,
+    AmpEnvelope,
+    FilterEnvelope,
+    WarpEnvelope,
+    Lfo1,
+    Lfo2,
+    Velocity,
+    Key,
+    ModWheel,
+    Aftertouch,
+}
+
+impl Choice for ModSource {
+    const ALL: &'static [Self] = &[
+        ModSource::None,
+        ModSource::AmpEnvelope,
+        ModSource::FilterEnvelope,
+        ModSource::WarpEnvelope,
+        ModSource::Lfo1,
+        ModSource::Lfo2,
+        ModSource::Velocity,
+        ModSource::Key,
+        ModSource::ModWheel,
+        ModSource::Aftertouch,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            ModSource::None => "None",
+            ModSource::AmpEnvelope => "Amp Envelope",
+            ModSource::FilterEnvelope => "Filter Envelope",
+            ModSource::WarpEnvelope => "Warp Envelope",
+            ModSource::Lfo1 => "LFO 1",
+            ModSource::Lfo2 => "LFO 2",
+            ModSource::Velocity => "Velocity",
+            ModSource::Key => "Key",
+            ModSource::ModWheel => "Mod Wheel",
+            ModSource::Aftertouch => "Aftertouch",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ModDestination {
+    None,
+    Pitch,
+    Amp,
+    Cutoff,
+    Resonance,
+    FilterEnvAmount,
+    WaveWarp,
+    Lfo1Rate,
+    Lfo2Rate,
+}
+
+const NUM_DESTINATIONS: usize = 9;
+
+impl Choice for ModDestination {
+    const ALL: &'static [Self] = &[
+        ModDestination::None,
+        ModDestination::Pitch,
+        ModDestination::Amp,
+        ModDestination::Cutoff,
+        ModDestination::Resonance,
+        ModDestination::FilterEnvAmount,
+        ModDestination::WaveWarp,
+        ModDestination::Lfo1Rate,
+        ModDestination::Lfo2Rate,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            ModDestination::None => "None",
+            ModDestination::Pitch => "Pitch",
+            ModDestination::Amp => "Amp",
+            ModDestination::Cutoff => "Filter Cutoff",
+            ModDestination::Resonance => "Filter Resonance",
+            ModDestination::FilterEnvAmount => "Filter Env Amount",
+            ModDestination::WaveWarp => "Wave Warp",
+            ModDestination::Lfo1Rate => "LFO 1 Rate",
+            ModDestination::Lfo2Rate => "LFO 2 Rate",
+        }
+    }
+}
+
+// the rate destination of each lfo
+pub const LFO_RATE_DESTINATIONS: [ModDestination; NUM_LFOS] =
+    [ModDestination::Lfo1Rate, ModDestination::Lfo2Rate];
+
+// The value of every source for one sample of a voice.
+// Envelopes, velocity, mod wheel and aftertouch go from 0.0 to 1.0,
+// the lfos and the key from -1.0 to 1.0.
+pub struct ModSources {
+    pub amp_envelope: f32,
+    pub filter_envelope: f32,
+    pub warp_envelope: f32,
+    pub lfos: [f32; NUM_LFOS],
+    pub velocity: f32,
+    pub key: f32,
+    pub mod_wheel: f32,
+    pub aftertouch: f32,
+}
+
+impl ModSources {
+    fn get(&self, source: ModSource) -> f32 {
+        match source {
+            // no via source lets the slot through unchanged
+            ModSource::None => 1.0,
+            ModSource::AmpEnvelope => self.amp_envelope,
+            ModSource::FilterEnvelope => self.filter_envelope,
+            ModSource::WarpEnvelope => self.warp_envelope,
+            ModSource::Lfo1 => self.lfos[0],
+            ModSource::Lfo2 => self.lfos[1],
+            ModSource::Velocity => self.velocity,
+            ModSource::Key => self.key,
+            ModSource::ModWheel => self.mod_wheel,
+            ModSource::Aftertouch => self.aftertouch,
+        }
+    }
+}
+
+// The sum of all of the slots for each destination
+#[derive(Clone, Copy)]
+pub struct Modulation {
+    values: [f32; NUM_DESTINATIONS],
+}
+
+impl Modulation {
+    pub fn new() -> Self {
+        Modulation {
+            values: [0.0; NUM_DESTINATIONS],
+        }
+    }
+
+    pub fn get(&self, destination: ModDestination) -> f32 {
+        self.values[destination as usize]
+    }
+}
+
+#[derive(Clone, Copy)]
+struct ModSlot {
+    source: ModSource,
+    destination: ModDestination,
+    // -1.0 to 1.0
+    amount: f32,
+    via: ModSource,
+}
+
+pub struct ModMatrix {
+    slots: [ModSlot; NUM_MOD_SLOTS],
+}
+
+impl ModMatrix {
+    // read the slots from the parameters
+    pub fn new(params: &PluginState) -> Self {
+        let slots = std::array::from_fn(|i| {
+            let slot = &params.mod_slots[i];
+            ModSlot {
+                source: ModSource::from_parameter(slot.source.get()),
+                destination: ModDestination::from_parameter(slot.destination.get()),
+                amount: BIPOLAR_AMOUNT.value(slot.amount.get()),
+                via: ModSource::from_parameter(slot.via.get()),
+            }
+        });
+        ModMatrix { slots }
+    }
+
+    fn active_slots(&self) -> impl Iterator<Item = &ModSlot> {
+        self.slots.iter().filter(|slot| {
+            slot.source != ModSource::None
+                && slot.destination != ModDestination::None
+                && slot.amount != 0.0
+        })
+    }
+
+    // whether any of the slots do something to *destination*
+    pub fn targets(&self, destination: ModDestination) -> bool {
+        self.active_slots()
+            .any(|slot| slot.destination == destination)
+    }
+
+    pub fn process(&self, sources: &ModSources) -> Modulation {
+        let mut modulation = Modulation::new();
+        for slot in self.active_slots() {
+            modulation.values[slot.destination as usize] +=
+                slot.amount * sources.get(slot.source) * sources.get(slot.via);
+        }
+        modulation
+    }
+}
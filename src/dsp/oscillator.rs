@@ -8,7 +8,8 @@ lazy_static! {
 }
 
 pub trait Oscillator {
-    fn process(&self, time: f32) -> f32;
+    // produce the next sample
+    fn process(&mut self) -> f32;
 }
 
 #[derive(RustEmbed)]
@@ -43,25 +44,50 @@ pub struct Wave {
 /*
  * Basic Wave Table Oscillator
  *
+ * The oscillator keeps its own phase, which is advanced once per sample.
+ * This way the position in the table never depends on the (imprecise)
+ * time since note on and changing the frequency does not reset the wave.
  */
 pub struct WaveTableOscillator {
     sample_rate: f32,
-    samples_per_cycle: f32,
-    scale_factor: f32,
+    // position within one cycle, 0 <= phase < 1
+    phase: f64,
+    phase_increment: f64,
     scaled_warp: f32,
-    wave_index_a: f32,
-    wave_index_b: f32,
+    wave_index_a: usize,
+    wave_index_b: usize,
 }
 
 impl WaveTableOscillator {
     pub fn new(frequency: f32, sample_rate: f32, wave_warp: f32) -> Self {
-        let samples_per_cycle = sample_rate / frequency;
-        let scale_factor = WAVE_TABLE_LENGTH as f32 / samples_per_cycle;
+        let mut oscillator = WaveTableOscillator {
+            sample_rate,
+            phase: 0.0,
+            phase_increment: 0.0,
+            scaled_warp: 0.0,
+            wave_index_a: 0,
+            wave_index_b: 1,
+        };
+        oscillator.set_frequency(frequency);
+        oscillator.set_wave_warp(wave_warp);
+        oscillator
+    }
 
+    pub fn set_frequency(&mut self, frequency: f32) {
+        self.phase_increment = f64::from(frequency) / f64::from(self.sample_rate);
+    }
+
+    // keep playing the same frequency at the new rate
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.phase_increment *= f64::from(self.sample_rate) / f64::from(sample_rate);
+        self.sample_rate = sample_rate;
+    }
+
+    pub fn set_wave_warp(&mut self, wave_warp: f32) {
         // wave_warp is a float between 0.0 and 1.0. We want to use this to
         // switch between N waves
-        let mut wave_index_a = 0.0;
-        let mut wave_index_b = 1.0;
+        let mut wave_index_a = 0;
+        let mut wave_index_b = 1;
         let mut scaled_warp = wave_warp;
 
         let n_waves = WAVE_TABLE.waves.len();
@@ -70,15 +96,12 @@ impl WaveTableOscillator {
         // there is probably a way to do this arithmetically
         if n_waves > 2 {
             for i in 0..(n_waves - 1) {
-                let maybe_a = i as f32;
-                let maybe_b = (i + 1) as f32;
-
-                let maybe_a_threshold = maybe_a * wave_width;
-                let maybe_b_threshold = maybe_b * wave_width;
+                let maybe_a_threshold = i as f32 * wave_width;
+                let maybe_b_threshold = (i + 1) as f32 * wave_width;
 
                 if wave_warp <= maybe_b_threshold {
-                    wave_index_a = maybe_a;
-                    wave_index_b = maybe_b;
+                    wave_index_a = i;
+                    wave_index_b = i + 1;
 
                     scaled_warp = (wave_warp - maybe_a_threshold) / wave_width;
                     break;
@@ -86,27 +109,21 @@ impl WaveTableOscillator {
             }
         }
 
-        WaveTableOscillator {
-            sample_rate,
-            samples_per_cycle,
-            scale_factor,
-            scaled_warp,
-            wave_index_a,
-            wave_index_b,
-        }
+        self.wave_index_a = wave_index_a;
+        self.wave_index_b = wave_index_b;
+        self.scaled_warp = scaled_warp;
     }
 }
 
 impl Oscillator for WaveTableOscillator {
-    fn process(&self, time: f32) -> f32 {
-        let total_sample_offset = time * self.sample_rate;
-        let unscaled_sample_offset = total_sample_offset % self.samples_per_cycle;
+    fn process(&mut self) -> f32 {
+        let table_offset = (self.phase * WAVE_TABLE_LENGTH as f64) as usize % WAVE_TABLE_LENGTH;
 
-        // hopefully the way we do the rounding won't land us out of bounds
-        let table_offset = unscaled_sample_offset * self.scale_factor;
+        let sample_a = WAVE_TABLE.waves[self.wave_index_a].samples[table_offset];
+        let sample_b = WAVE_TABLE.waves[self.wave_index_b].samples[table_offset];
 
-        let sample_a = WAVE_TABLE.waves[self.wave_index_a as usize].samples[table_offset as usize];
-        let sample_b = WAVE_TABLE.waves[self.wave_index_b as usize].samples[table_offset as usize];
+        self.phase += self.phase_increment;
+        self.phase -= self.phase.floor();
 
         let delta = sample_b - sample_a;
         sample_a + delta * self.scaled_warp
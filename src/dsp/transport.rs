@@ -0,0 +1,89 @@
+// Where the host's transport is, so the dsp can follow the tempo of the project.
+
+use vst::api::{TimeInfo, TimeInfoFlags};
+
+// what we assume until the host tells us otherwise
+const DEFAULT_TEMPO: f64 = 120.0;
+
+// the fields of TimeInfo we ask the host to fill in
+pub fn time_info_mask() -> i32 {
+    (TimeInfoFlags::TEMPO_VALID | TimeInfoFlags::PPQ_POS_VALID | TimeInfoFlags::BARS_VALID).bits()
+}
+
+pub struct Transport {
+    // beats per minute
+    pub tempo: f64,
+    // position in quarter notes, at the start of what is rendered next
+    pub ppq_position: f64,
+    // position of the last bar line in quarter notes
+    bar_start: f64,
+    pub playing: bool,
+}
+
+impl Transport {
+    pub fn new() -> Self {
+        Transport {
+            tempo: DEFAULT_TEMPO,
+            ppq_position: 0.0,
+            bar_start: 0.0,
+            playing: false,
+        }
+    }
+
+    // Take whatever the host knows at the start of a block.
+    // Hosts don't have to fill in every field, so the ones that
+    // aren't valid keep the value we had.
+    pub fn update(&mut self, time_info: Option<TimeInfo>) {
+        let time_info = match time_info {
+            Some(time_info) => time_info,
+            None => return,
+        };
+        let flags = TimeInfoFlags::from_bits_truncate(time_info.flags);
+
+        self.playing = flags.contains(TimeInfoFlags::TRANSPORT_PLAYING);
+        if flags.contains(TimeInfoFlags::TEMPO_VALID) && time_info.tempo > 0.0 {
+            self.tempo = time_info.tempo;
+        }
+        if flags.contains(TimeInfoFlags::PPQ_POS_VALID) {
+            self.ppq_position = time_info.ppq_pos;
+        }
+        if flags.contains(TimeInfoFlags::BARS_VALID) {
+            self.bar_start = time_info.bar_start_pos;
+        }
+    }
+
+    // Where a synced cycle of *beats* quarter notes is, counted from the last
+    // bar line, so that a whole note cycle starts on every bar even in 3/4.
+    pub fn phase(&self, beats: f64) -> f64 {
+        (self.ppq_position - self.bar_start) / beats
+    }
+
+    // how many quarter notes go by in *samples*
+    pub fn beats(&self, samples: usize, sample_rate: f32) -> f64 {
+        samples as f64 / f64::from(sample_rate) * self.tempo / 60.0
+    }
+
+    // move the position along after rendering *samples*
+    pub fn advance(&mut self, samples: usize, sample_rate: f32) {
+        if self.playing {
+            self.ppq_position += self.beats(samples, sample_rate);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn synced_cycles_start_on_the_bar() {
+        // the second bar of 3/4 starts at the fourth quarter note
+        let transport = Transport {
+            ppq_position: 4.0,
+            bar_start: 3.0,
+            ..Transport::new()
+        };
+        // a cycle of a whole note is a quarter of the way in, not back at the start
+        assert_eq!(transport.phase(4.0), 0.25);
+    }
+}
@@ -4,73 +4,898 @@
 // Each one can have multiple oscillators and other moodules.
 
 use crate::dsp::envelope::{Envelope, ADSR};
+use crate::dsp::filter::{cutoff_offset, Filter, FilterMode, StateVariableFilter};
+use crate::dsp::lfo::{Lfo, LfoMode, LfoSettings};
+use crate::dsp::mod_matrix::{
+    ModDestination, ModMatrix, ModSources, Modulation, LFO_RATE_DESTINATIONS,
+    MAX_MOD_LFO_RATE_OCTAVES, MAX_MOD_PITCH,
+};
 use crate::dsp::oscillator::{Oscillator, WaveTableOscillator};
-use crate::notes::Note;
-use crate::plugin_state::PluginState;
+use crate::notes::{Note, NotePriority, Notebook, VelocityCurve};
+use crate::parameters::{BIPOLAR_AMOUNT, ENVELOPE_TIME, GLIDE_TIME, PEAK_GAIN};
+use crate::plugin_state::{Choice, PluginState, NUM_LFOS};
 
 use std::sync::Arc;
 
-fn midi_pitch_to_freq(pitch: u8) -> f32 {
-    const A4_PITCH: i8 = 69;
+// the pitch doesn't have to be a whole note, so we can glide between notes
+fn midi_pitch_to_freq(pitch: f32) -> f32 {
+    const A4_PITCH: f32 = 69.0;
     const A4_FREQ: f32 = 440.0;
 
     // Midi notes can be 0-127
-    ((f32::from(pitch as i8 - A4_PITCH)) / 12.).exp2() * A4_FREQ
+    ((pitch - A4_PITCH) / 12.).exp2() * A4_FREQ
 }
 
+// with full key tracking, the cutoff is unchanged for this note
+const KEY_TRACK_PITCH: u8 = 60;
+
+// pitch bend messages are coarse, so they are smoothed over about this long
+const PITCH_BEND_SMOOTHING_TIME: f32 = 0.01;
+
+// the pool is allocated once, so this is the most voices we can ever play
+pub const MAX_VOICES: usize = 32;
+
+// the warp modulation at full depth moves half way across the waves
+pub const MAX_WARP_DEPTH: f32 = 0.5;
+
 pub struct Voice {
     note: Note,
     params: Arc<PluginState>,
     sample_rate: f32,
     wave_warp: f32,
+    oscillator: WaveTableOscillator,
+    filter: StateVariableFilter,
+    lfos: [Lfo; NUM_LFOS],
+    // the envelopes keep running across blocks, their times are read every block
+    envelope: ADSR,
+    filter_envelope: ADSR,
+    warp_envelope: ADSR,
+    // the mod matrix output of the last sample, which carries over into the next block
+    modulation: Modulation,
+
+    // a voice is active until its release is finished
+    active: bool,
+    on: bool,
+    // time since note on and the time stamp of note off, in seconds
+    time: f64,
+    off_time: f64,
+    // the most recent value of the amp envelope
+    level: f32,
+
+    // the pitch we are at and the pitch we are gliding to, in midi notes
+    pitch: f32,
+    target_pitch: f32,
+    // how far the pitch moves every sample
+    glide_rate: f32,
+    // pitch bend in semitones, on top of the pitch
+    pitch_bend: f32,
+    pitch_bend_smoothing: f32,
+
+    output: Vec<f32>,
 }
 
 impl Voice {
-    pub fn new(note: Note, params: Arc<PluginState>) -> Voice {
+    pub fn new(params: Arc<PluginState>) -> Voice {
         let sample_rate = params.sample_rate.get();
-        let wave_warp = params.wave_warp.get();
+        let wave_warp = params.global.wave_warp.get();
+        // placeholder until the voice plays its first note
+        let note = Note {
+            id: 0,
+            channel: 0,
+            number: KEY_TRACK_PITCH,
+            velocity: 0,
+            pressure: 0,
+            bend: 0.0,
+            slide: 0,
+            key_down: false,
+            sostenuto: false,
+        };
+        let oscillator =
+            WaveTableOscillator::new(midi_pitch_to_freq(note.pitch()), sample_rate, wave_warp);
+        let filter = StateVariableFilter::new(
+            FilterMode::from_parameter(params.global.filter_mode.get()),
+            params.global.filter_cutoff.get(),
+            params.global.filter_resonance.get(),
+            sample_rate,
+        );
         Voice {
             note,
             params,
             sample_rate,
             wave_warp,
+            oscillator,
+            filter,
+            lfos: [Lfo::new(), Lfo::new()],
+            envelope: ADSR::new(0.0, 0.0, 0.0, 0.0),
+            filter_envelope: ADSR::new(0.0, 0.0, 0.0, 0.0),
+            warp_envelope: ADSR::new(0.0, 0.0, 0.0, 0.0),
+            modulation: Modulation::new(),
+            active: false,
+            on: false,
+            time: 0.0,
+            off_time: 0.0,
+            level: 0.0,
+            pitch: note.pitch(),
+            target_pitch: note.pitch(),
+            glide_rate: 0.0,
+            pitch_bend: 0.0,
+            pitch_bend_smoothing: (-1.0 / (PITCH_BEND_SMOOTHING_TIME * sample_rate)).exp(),
+            output: Vec::new(),
+        }
+    }
+
+    // Start (or restart) the envelopes for *note*.
+    // With *glide* the pitch slides over from the previous note.
+    pub fn note_on(&mut self, note: Note, glide: bool) {
+        let glide = glide && self.active;
+        if !self.active {
+            // don't bend in from wherever the last note was
+            self.pitch_bend = self.target_bend(&note);
         }
+        self.change_note(note, glide);
+        for lfo in self.lfos.iter_mut() {
+            lfo.reset();
+        }
+        self.envelope.reset();
+        self.filter_envelope.reset();
+        self.warp_envelope.reset();
+        self.modulation = Modulation::new();
+        self.active = true;
+        self.on = true;
+        self.time = 0.0;
+        self.off_time = 0.0;
+    }
+
+    // Switch to another note without restarting the envelopes.
+    pub fn change_note(&mut self, note: Note, glide: bool) {
+        self.note = note;
+        self.target_pitch = note.pitch();
+
+        let glide_time = GLIDE_TIME.value(self.params.global.glide_time.get());
+        if !glide || glide_time <= 0.0 {
+            self.pitch = self.target_pitch;
+            self.glide_rate = 0.0;
+            return;
+        }
+
+        let glide_samples = glide_time * self.sample_rate;
+        self.glide_rate = match GlideMode::from_parameter(self.params.global.glide_mode.get()) {
+            GlideMode::ConstantTime => (self.target_pitch - self.pitch).abs() / glide_samples,
+            // with a constant rate the glide time is the time for one octave
+            GlideMode::ConstantRate => 12.0 / glide_samples,
+        };
+    }
+
+    pub fn note_off(&mut self) {
+        self.on = false;
+        self.off_time = self.time;
+    }
+
+    // Everything that depends on the sample rate is recomputed,
+    // so a playing voice keeps its pitch and timing.
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.glide_rate *= self.sample_rate / sample_rate;
+        self.pitch_bend_smoothing = (-1.0 / (PITCH_BEND_SMOOTHING_TIME * sample_rate)).exp();
+        self.oscillator.set_sample_rate(sample_rate);
+        self.filter.set_sample_rate(sample_rate);
+        self.sample_rate = sample_rate;
+    }
+
+    pub fn reserve(&mut self, buffer_size: usize) {
+        self.output.reserve(buffer_size);
+    }
+
+    // stop right away, without a release
+    pub fn silence(&mut self) {
+        self.active = false;
+        self.on = false;
+        self.level = 0.0;
+        self.filter.reset();
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on
     }
-    pub fn play(&self, buffer_len: usize) -> Vec<f32> {
-        // TODO should probably make a local copy of sample rate
-        // so that we don't have to get this lock every time
-        let time_per_sample = 1.0 / self.sample_rate;
 
-        let mut warp_envelope = ADSR::new(
-            self.params.warp_attack.get(),
-            self.params.warp_decay.get(),
-            self.params.warp_sustain.get(),
-            self.params.warp_release.get(),
+    // *free_lfos* are where the free running lfos are at the start of the block
+    pub fn play(&mut self, buffer_len: usize, free_lfos: &[Lfo], tempo: f64) -> &[f32] {
+        let time_per_sample = 1.0 / f64::from(self.sample_rate);
+        let time = self.time as f32;
+        let off_time = self.off_time as f32;
+        self.wave_warp = self.params.global.wave_warp.get();
+
+        // with no sensitivity every note is played as if at full velocity
+        let curve = VelocityCurve::from_parameter(self.params.global.velocity_curve.get());
+        let velocity = curve.apply(self.note.velocity);
+        let velocity_scale = |sensitivity: f32| 1.0 - sensitivity + sensitivity * velocity;
+        let velocity_amp = velocity_scale(self.params.global.velocity_amp.get());
+        let velocity_filter = velocity_scale(self.params.global.velocity_filter.get());
+        let velocity_warp = velocity_scale(self.params.global.velocity_warp.get());
+
+        // aftertouch is whichever is stronger, the key or the channel pressure
+        let pressure =
+            (f32::from(self.note.pressure) / 127.0).max(self.params.channel_pressure.get());
+        let aftertouch_volume = self.params.global.aftertouch_volume.get();
+        let pressure_amp = 1.0 - aftertouch_volume + aftertouch_volume * pressure;
+        let pressure_cutoff =
+            BIPOLAR_AMOUNT.value(self.params.global.aftertouch_cutoff.get()) * pressure;
+        let pressure_warp = BIPOLAR_AMOUNT.value(self.params.global.aftertouch_warp.get())
+            * MAX_WARP_DEPTH
+            * pressure;
+
+        // mpe slide works like aftertouch, but it doesn't touch the volume
+        let slide = f32::from(self.note.slide) / 127.0;
+        let slide_cutoff = BIPOLAR_AMOUNT.value(self.params.global.slide_cutoff.get()) * slide;
+        let slide_warp =
+            BIPOLAR_AMOUNT.value(self.params.global.slide_warp.get()) * MAX_WARP_DEPTH * slide;
+
+        self.warp_envelope.set_parameters(
+            self.params.global.warp_attack.get(),
+            self.params.global.warp_decay.get(),
+            self.params.global.warp_sustain.get(),
+            self.params.global.warp_release.get(),
         );
-        let warp_alpha = (self.params.warp_ratio.get() - 0.5)
-            * warp_envelope.process(self.note.time, self.note.on, self.note.off_time);
+        let warp_alpha = BIPOLAR_AMOUNT.value(self.params.global.warp_ratio.get())
+            * MAX_WARP_DEPTH
+            * velocity_warp
+            * self.warp_envelope.process(time, self.on, off_time);
+
+        let wave_warp = self.wave_warp + warp_alpha + pressure_warp + slide_warp;
+        self.oscillator.set_wave_warp(wave_warp.max(-1.0).min(1.0));
 
-        let oscillator = WaveTableOscillator::new(
-            midi_pitch_to_freq(self.note.number),
-            self.sample_rate,
-            (self.wave_warp + warp_alpha).max(-1.0).min(1.0),
+        let lfo_settings: [LfoSettings; NUM_LFOS] = std::array::from_fn(|i| {
+            LfoSettings::new(&self.params.lfos[i], tempo, self.sample_rate)
+        });
+        for (i, settings) in lfo_settings.iter().enumerate() {
+            if settings.mode == LfoMode::FreeRunning {
+                self.lfos[i] = free_lfos[i].clone();
+            }
+        }
+
+        let matrix = ModMatrix::new(&self.params);
+        // picks up where the last block left off
+        let mut modulation = self.modulation;
+        let key = (self.note.pitch() - 64.0) / 64.0;
+        let mod_wheel = self.params.mod_wheel.get();
+
+        // only recompute these every sample when something is moving them
+        let warp_moves = lfo_settings.iter().any(|settings| settings.warp != 0.0)
+            || matrix.targets(ModDestination::WaveWarp);
+        let pitch_moves = lfo_settings.iter().any(|settings| settings.pitch != 0.0)
+            || matrix.targets(ModDestination::Pitch);
+        let resonance_moves = matrix.targets(ModDestination::Resonance);
+        let resonance = self.params.global.filter_resonance.get();
+
+        self.envelope.set_parameters(
+            self.params.global.attack.get(),
+            self.params.global.decay.get(),
+            self.params.global.sustain.get(),
+            self.params.global.release.get(),
         );
-        let mut envelope = ADSR::new(
-            self.params.attack.get(),
-            self.params.decay.get(),
-            self.params.sustain.get(),
-            self.params.release.get(),
+
+        self.filter_envelope.set_parameters(
+            self.params.global.filter_attack.get(),
+            self.params.global.filter_decay.get(),
+            self.params.global.filter_sustain.get(),
+            self.params.global.filter_release.get(),
         );
+        let filter_cutoff = self.params.global.filter_cutoff.get() * velocity_filter
+            + pressure_cutoff
+            + slide_cutoff;
+        let filter_env_amount = BIPOLAR_AMOUNT.value(self.params.global.filter_env_amount.get());
+
+        // key tracking moves the cutoff along with the pitch of the note
+        let key_track = cutoff_offset(
+            (midi_pitch_to_freq(self.note.pitch())
+                / midi_pitch_to_freq(f32::from(KEY_TRACK_PITCH)))
+            .powf(self.params.global.filter_key_track.get()),
+        );
+
+        self.filter.set_mode(FilterMode::from_parameter(
+            self.params.global.filter_mode.get(),
+        ));
+        self.filter
+            .set_resonance(self.params.global.filter_resonance.get());
+        self.filter
+            .set_peak_gain(PEAK_GAIN.value(self.params.global.filter_peak_gain.get()));
+
+        self.output.clear();
+
+        self.oscillator
+            .set_frequency(midi_pitch_to_freq(self.pitch + self.pitch_bend));
+        let target_bend = self.target_bend(&self.note);
+
+        for _ in 0..buffer_len {
+            let time = self.time as f32;
+
+            let mut lfo_values = [0.0; NUM_LFOS];
+            let mut lfo_warp = 0.0;
+            let mut lfo_cutoff = 0.0;
+            let mut lfo_pitch = 0.0;
+            let mut lfo_amp = 1.0;
+            for (i, (lfo, settings)) in self.lfos.iter_mut().zip(lfo_settings.iter()).enumerate() {
+                // the rate modulation is from the previous sample, since the lfos are sources too
+                let rate =
+                    (modulation.get(LFO_RATE_DESTINATIONS[i]) * MAX_MOD_LFO_RATE_OCTAVES).exp2();
+                let value = lfo.process(settings, time, f64::from(rate));
+                lfo_values[i] = value;
+                lfo_warp += settings.warp * value;
+                lfo_cutoff += settings.cutoff * value;
+                lfo_pitch += settings.pitch * value;
+                // at full depth the volume dips all the way down at the bottom of the lfo
+                lfo_amp *= 1.0 - settings.amp * 0.5 * (1.0 - value);
+            }
+
+            let amp_level = self.envelope.process(time, self.on, off_time);
+            let filter_level = self.filter_envelope.process(time, self.on, off_time);
+            modulation = matrix.process(&ModSources {
+                amp_envelope: amp_level,
+                filter_envelope: filter_level,
+                warp_envelope: self.warp_envelope.process(time, self.on, off_time),
+                lfos: lfo_values,
+                velocity,
+                key,
+                mod_wheel,
+                aftertouch: pressure,
+            });
+
+            let pitch_moved = self.pitch != self.target_pitch || self.pitch_bend != target_bend;
+            if pitch_moved {
+                self.update_pitch(target_bend);
+            }
+            if pitch_moved || pitch_moves {
+                let mod_pitch = modulation.get(ModDestination::Pitch) * MAX_MOD_PITCH;
+                self.oscillator.set_frequency(midi_pitch_to_freq(
+                    self.pitch + self.pitch_bend + lfo_pitch + mod_pitch,
+                ));
+            }
+            if warp_moves {
+                let warp = wave_warp + lfo_warp + modulation.get(ModDestination::WaveWarp);
+                self.oscillator.set_wave_warp(warp.max(-1.0).min(1.0));
+            }
+            if resonance_moves {
+                self.filter
+                    .set_resonance(resonance + modulation.get(ModDestination::Resonance));
+            }
+
+            let mod_amp = (1.0 + modulation.get(ModDestination::Amp)).max(0.0);
+            let alpha = velocity_amp * pressure_amp * amp_level;
+
+            let filter_alpha = (filter_env_amount
+                + modulation.get(ModDestination::FilterEnvAmount))
+                * filter_level;
+            let cutoff =
+                filter_cutoff + filter_alpha + lfo_cutoff + modulation.get(ModDestination::Cutoff);
+            self.filter.set_cutoff(cutoff + key_track);
+
+            let sample = alpha * lfo_amp * mod_amp * self.filter.process(self.oscillator.process());
+
+            self.output.push(sample);
+            self.level = alpha;
+            self.time += time_per_sample;
+        }
+        self.modulation = modulation;
+
+        // once the release is over the voice can be used again
+        let release = f64::from(ENVELOPE_TIME.value(self.params.global.release.get()));
+        if !self.on && self.time - self.off_time >= release {
+            self.active = false;
+        }
+
+        &self.output
+    }
+
+    // the global pitch bend plus the bend of the note itself in mpe mode
+    fn target_bend(&self, note: &Note) -> f32 {
+        self.params.pitch_bend_semitones() + self.params.mpe_bend_semitones(note.bend)
+    }
+
+    // move the pitch and the pitch bend one sample closer to their targets
+    fn update_pitch(&mut self, target_bend: f32) {
+        let distance = self.target_pitch - self.pitch;
+        if distance.abs() <= self.glide_rate {
+            self.pitch = self.target_pitch;
+        } else {
+            self.pitch += self.glide_rate.copysign(distance);
+        }
+
+        self.pitch_bend = target_bend + self.pitch_bend_smoothing * (self.pitch_bend - target_bend);
+        if (self.pitch_bend - target_bend).abs() < 0.0001 {
+            self.pitch_bend = target_bend;
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum PlayMode {
+    Poly,
+    Mono,
+    // like mono, but overlapping notes don't restart the envelopes
+    Legato,
+}
+
+impl Choice for PlayMode {
+    const ALL: &'static [Self] = &[PlayMode::Poly, PlayMode::Mono, PlayMode::Legato];
+
+    fn name(self) -> &'static str {
+        match self {
+            PlayMode::Poly => "Poly",
+            PlayMode::Mono => "Mono",
+            PlayMode::Legato => "Legato",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum GlideMode {
+    ConstantTime,
+    ConstantRate,
+}
+
+impl Choice for GlideMode {
+    const ALL: &'static [Self] = &[GlideMode::ConstantTime, GlideMode::ConstantRate];
+
+    fn name(self) -> &'static str {
+        match self {
+            GlideMode::ConstantTime => "Constant Time",
+            GlideMode::ConstantRate => "Constant Rate",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum StealPolicy {
+    Oldest,
+    Quietest,
+    Lowest,
+    Highest,
+    ReleasedFirst,
+}
+
+impl Choice for StealPolicy {
+    const ALL: &'static [Self] = &[
+        StealPolicy::Oldest,
+        StealPolicy::Quietest,
+        StealPolicy::Lowest,
+        StealPolicy::Highest,
+        StealPolicy::ReleasedFirst,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            StealPolicy::Oldest => "Oldest",
+            StealPolicy::Quietest => "Quietest",
+            StealPolicy::Lowest => "Lowest",
+            StealPolicy::Highest => "Highest",
+            StealPolicy::ReleasedFirst => "Released First",
+        }
+    }
+}
+
+// Pick the active voice that has to make room for a new note.
+fn steal_voice(voices: &[Voice], policy: StealPolicy) -> Option<usize> {
+    let active = voices
+        .iter()
+        .enumerate()
+        .filter(|(_, voice)| voice.is_active());
+
+    let stolen = match policy {
+        StealPolicy::Oldest => active.min_by_key(|(_, voice)| voice.note.id),
+        StealPolicy::Quietest => active.min_by(|(_, a), (_, b)| a.level.total_cmp(&b.level)),
+        StealPolicy::Lowest => active.min_by_key(|(_, voice)| voice.note.number),
+        StealPolicy::Highest => active.max_by_key(|(_, voice)| voice.note.number),
+        // released voices sort before held ones, then the oldest goes first
+        StealPolicy::ReleasedFirst => active.min_by_key(|(_, voice)| (voice.on, voice.note.id)),
+    };
+    stolen.map(|(index, _)| index)
+}
+
+// All of the voices are allocated up front so nothing
+// has to be allocated while processing audio.
+pub struct VoicePool {
+    voices: Vec<Voice>,
+    // every note up to this id has already been given a voice
+    last_note_id: u64,
+}
 
-        let mut output = Vec::with_capacity(buffer_len);
+impl VoicePool {
+    pub fn new(params: Arc<PluginState>) -> Self {
+        let voices = (0..MAX_VOICES)
+            .map(|_| Voice::new(params.clone()))
+            .collect();
+        VoicePool {
+            voices,
+            last_note_id: 0,
+        }
+    }
+
+    // Release the voices whose notes are gone from the notebook
+    // and start voices for the notes that are new.
+    pub fn update(&mut self, notebook: &Notebook, polyphony: usize, policy: StealPolicy) {
+        for voice in self.voices.iter_mut() {
+            match notebook.find(voice.note.id) {
+                // pick up the latest expression of the note
+                Some(note) => voice.note = note,
+                None if voice.is_on() => voice.note_off(),
+                None => (),
+            }
+        }
+
+        // the polyphony might have been turned down
+        while self.active_count() > polyphony {
+            if let Some(index) = steal_voice(&self.voices, policy) {
+                self.voices[index].active = false;
+            }
+        }
+
+        // start the new notes in the order they were played
+        while let Some(note) = notebook
+            .notes()
+            .filter(|note| note.id > self.last_note_id)
+            .min_by_key(|note| note.id)
+        {
+            self.last_note_id = note.id;
+
+            let index = if self.active_count() < polyphony {
+                self.voices.iter().position(|voice| !voice.is_active())
+            } else {
+                // a key that is struck again takes over the voice it is still releasing in
+                self.voices
+                    .iter()
+                    .position(|voice| {
+                        voice.is_active()
+                            && voice.note.channel == note.channel
+                            && voice.note.number == note.number
+                    })
+                    .or_else(|| steal_voice(&self.voices, policy))
+            };
+
+            if let Some(index) = index {
+                self.voices[index].note_on(*note, false);
+            }
+        }
+    }
+
+    // In mono and legato mode only the first voice is used.
+    // It plays whichever held note has priority.
+    pub fn update_mono(&mut self, notebook: &Notebook, priority: NotePriority, legato: bool) {
+        for voice in self.voices.iter_mut().skip(1) {
+            if voice.is_on() {
+                voice.note_off();
+            }
+        }
+
+        // don't start these notes if we switch back to poly
+        if let Some(note) = notebook.notes().max_by_key(|note| note.id) {
+            self.last_note_id = self.last_note_id.max(note.id);
+        }
+
+        let voice = &mut self.voices[0];
+        match notebook.priority_note(priority) {
+            Some(note) if note.id != voice.note.id || !voice.is_on() => {
+                if legato && voice.is_on() {
+                    voice.change_note(note, true);
+                } else {
+                    voice.note_on(note, true);
+                }
+            }
+            Some(note) => voice.note = note,
+            None => {
+                if voice.is_on() {
+                    voice.note_off();
+                }
+            }
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        for voice in self.voices.iter_mut() {
+            voice.set_sample_rate(sample_rate);
+        }
+    }
+
+    pub fn reserve(&mut self, buffer_size: usize) {
+        for voice in self.voices.iter_mut() {
+            voice.reserve(buffer_size);
+        }
+    }
+
+    pub fn silence(&mut self) {
+        for voice in self.voices.iter_mut() {
+            voice.silence();
+        }
+    }
+
+    pub fn active_voices_mut(&mut self) -> impl Iterator<Item = &mut Voice> {
+        self.voices.iter_mut().filter(|voice| voice.is_active())
+    }
+
+    fn active_count(&self) -> usize {
+        self.voices.iter().filter(|voice| voice.is_active()).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a pool and the notes that are held on the keyboard
+    struct Keyboard {
+        pool: VoicePool,
+        notebook: Notebook,
+        polyphony: usize,
+        policy: StealPolicy,
+    }
+
+    impl Keyboard {
+        fn new(polyphony: usize, policy: StealPolicy) -> Self {
+            let params = Arc::new(PluginState::default());
+            Keyboard {
+                pool: VoicePool::new(params),
+                notebook: Notebook::new(),
+                polyphony,
+                policy,
+            }
+        }
+
+        fn press(&mut self, notes: &[u8]) {
+            for number in notes {
+                self.notebook.note_on(0, *number, 100);
+                self.update();
+            }
+        }
+
+        fn release(&mut self, number: u8) {
+            self.notebook.note_off(0, number);
+            self.update();
+        }
+
+        fn update(&mut self) {
+            self.pool
+                .update(&self.notebook, self.polyphony, self.policy);
+        }
+
+        fn voice(&self, number: u8) -> Option<&Voice> {
+            self.pool
+                .voices
+                .iter()
+                .find(|voice| voice.is_active() && voice.note.number == number)
+        }
+
+        // the note numbers of the active voices, lowest first
+        fn playing(&self) -> Vec<u8> {
+            let mut notes: Vec<u8> = self
+                .pool
+                .voices
+                .iter()
+                .filter(|voice| voice.is_active())
+                .map(|voice| voice.note.number)
+                .collect();
+            notes.sort_unstable();
+            notes
+        }
+    }
+
+    #[test]
+    fn steals_the_oldest_voice() {
+        let mut keyboard = Keyboard::new(3, StealPolicy::Oldest);
+        keyboard.press(&[60, 64, 67, 72]);
+        assert_eq!(keyboard.playing(), vec![64, 67, 72]);
+    }
+
+    #[test]
+    fn steals_the_quietest_voice() {
+        let mut keyboard = Keyboard::new(3, StealPolicy::Quietest);
+        keyboard.press(&[60, 64, 67]);
+        for voice in keyboard.pool.active_voices_mut() {
+            voice.level = if voice.note.number == 64 { 0.1 } else { 0.5 };
+        }
+        keyboard.press(&[72]);
+        assert_eq!(keyboard.playing(), vec![60, 67, 72]);
+    }
+
+    #[test]
+    fn steals_the_lowest_voice() {
+        let mut keyboard = Keyboard::new(3, StealPolicy::Lowest);
+        keyboard.press(&[64, 60, 67, 72]);
+        assert_eq!(keyboard.playing(), vec![64, 67, 72]);
+    }
+
+    #[test]
+    fn steals_the_highest_voice() {
+        let mut keyboard = Keyboard::new(3, StealPolicy::Highest);
+        keyboard.press(&[64, 72, 60, 67]);
+        assert_eq!(keyboard.playing(), vec![60, 64, 67]);
+    }
+
+    #[test]
+    fn steals_released_voices_first() {
+        let mut keyboard = Keyboard::new(3, StealPolicy::ReleasedFirst);
+        keyboard.press(&[60, 64, 67]);
+        // the voice is still playing its release
+        keyboard.release(64);
+        assert_eq!(keyboard.playing(), vec![60, 64, 67]);
+
+        keyboard.press(&[72]);
+        assert_eq!(keyboard.playing(), vec![60, 67, 72]);
+
+        // with nothing released it falls back to the oldest voice
+        keyboard.press(&[76]);
+        assert_eq!(keyboard.playing(), vec![67, 72, 76]);
+    }
+
+    #[test]
+    fn struck_key_takes_over_its_voice_when_full() {
+        let mut keyboard = Keyboard::new(3, StealPolicy::Oldest);
+        keyboard.press(&[60, 64, 67]);
+        keyboard.release(67);
+
+        // the oldest voice is left alone
+        keyboard.press(&[67]);
+        assert_eq!(keyboard.playing(), vec![60, 64, 67]);
+        assert!(keyboard.voice(67).unwrap().is_on());
+    }
+
+    #[test]
+    fn struck_key_gets_a_new_voice_when_there_is_room() {
+        let mut keyboard = Keyboard::new(4, StealPolicy::Oldest);
+        keyboard.press(&[60]);
+        keyboard.release(60);
+        keyboard.press(&[60]);
+        // the old note rings out next to the new one
+        assert_eq!(keyboard.playing(), vec![60, 60]);
+    }
+
+    #[test]
+    fn keeps_to_the_polyphony() {
+        let mut keyboard = Keyboard::new(4, StealPolicy::Oldest);
+        keyboard.press(&[60, 62, 64, 65, 67, 69]);
+        assert_eq!(keyboard.playing(), vec![64, 65, 67, 69]);
+
+        // turning the polyphony down steals the extra voices right away
+        keyboard.polyphony = 2;
+        keyboard.update();
+        assert_eq!(keyboard.playing(), vec![67, 69]);
+
+        keyboard.press(&[71]);
+        assert_eq!(keyboard.playing(), vec![69, 71]);
+    }
+
+    #[test]
+    fn plays_every_voice_at_full_polyphony() {
+        let mut keyboard = Keyboard::new(MAX_VOICES, StealPolicy::Oldest);
+        let notes: Vec<u8> = (40..).take(MAX_VOICES + 2).collect();
+        keyboard.press(&notes);
+        assert_eq!(keyboard.playing(), notes[2..].to_vec());
+    }
+
+    const SAMPLE_RATES: [f32; 3] = [44100.0, 48000.0, 96000.0];
+
+    fn note(number: u8) -> Note {
+        Note {
+            id: 1,
+            channel: 0,
+            number,
+            velocity: 100,
+            pressure: 0,
+            bend: 0.0,
+            slide: 0,
+            key_down: true,
+            sostenuto: false,
+        }
+    }
+
+    // a voice that plays at a steady volume right after the note on
+    fn voice(sample_rate: f32) -> Voice {
+        let params = Arc::new(PluginState::default());
+        params.global.attack.set(0.0);
+        params.global.decay.set(0.0);
+        params.global.sustain.set(1.0);
+        let mut voice = Voice::new(params);
+        voice.set_sample_rate(sample_rate);
+        voice
+    }
+
+    fn render(voice: &mut Voice, samples: usize) -> Vec<f32> {
+        let free_lfos = [Lfo::new(), Lfo::new()];
+        voice.play(samples, &free_lfos, 120.0).to_vec()
+    }
+
+    // The length of one period in samples, found where the signal is most like
+    // itself shifted by that much. Only periods close to *expected* are tried,
+    // so that multiples of the period are not picked up.
+    fn period(samples: &[f32], expected: f32) -> f32 {
+        let lags = (expected * 0.5) as usize..(expected * 1.5) as usize;
+        let length = samples.len() - lags.end - 1;
+        let difference = |lag: usize| -> f32 {
+            (0..length)
+                .map(|i| (samples[i] - samples[i + lag]).abs())
+                .sum()
+        };
 
-        for i in 0..buffer_len {
-            let time = self.note.time + (time_per_sample * (i as f32));
-            let alpha = envelope.process(time, self.note.on, self.note.off_time);
-            let sample = alpha * oscillator.process(time);
+        let lag = lags
+            .min_by(|a, b| difference(*a).total_cmp(&difference(*b)))
+            .unwrap();
+        // fit a parabola through the neighbours for a fraction of a sample
+        let (before, at, after) = (difference(lag - 1), difference(lag), difference(lag + 1));
+        lag as f32 + 0.5 * (before - after) / (before - 2.0 * at + after)
+    }
+
+    #[test]
+    fn plays_in_tune_at_every_sample_rate() {
+        for sample_rate in SAMPLE_RATES {
+            let mut voice = voice(sample_rate);
+            // A2
+            voice.note_on(note(45), false);
+            render(&mut voice, sample_rate as usize / 10);
+            let samples = render(&mut voice, sample_rate as usize / 10);
+
+            let frequency = sample_rate / period(&samples, sample_rate / 110.0);
+            assert!(
+                (frequency - 110.0).abs() < 0.2,
+                "{} Hz at {} Hz",
+                frequency,
+                sample_rate
+            );
+        }
+    }
+
+    #[test]
+    fn glides_for_the_same_time_at_every_sample_rate() {
+        const BLOCK: usize = 16;
+        for sample_rate in SAMPLE_RATES {
+            let mut voice = voice(sample_rate);
+            // 100 ms
+            voice.params.global.glide_time.set(0.1);
+            voice.note_on(note(48), false);
+            voice.note_on(note(60), true);
+
+            let mut blocks = 0;
+            while voice.pitch != voice.target_pitch {
+                render(&mut voice, BLOCK);
+                blocks += 1;
+            }
+            let glide_time = (blocks * BLOCK) as f32 / sample_rate;
+            assert!(
+                (glide_time - 0.1).abs() < 0.001,
+                "{} s at {} Hz",
+                glide_time,
+                sample_rate
+            );
+        }
+    }
+
+    #[test]
+    fn releasing_during_the_attack_does_not_jump_up() {
+        const BLOCK: usize = 64;
+        let mut voice = voice(48000.0);
+        // a long attack with full sustain, released a quarter of the way in
+        voice.params.global.attack.set(ENVELOPE_TIME.normalize(1.0));
+        voice
+            .params
+            .global
+            .release
+            .set(ENVELOPE_TIME.normalize(0.5));
+        voice.note_on(note(60), false);
+        for _ in 0..(12000 / BLOCK) {
+            render(&mut voice, BLOCK);
+        }
+        // still well below the sustain level
+        let level = voice.level;
+        assert!(level > 0.0 && level < 0.5, "{}", level);
 
-            output.push(sample);
+        voice.note_off();
+        // the next blocks only go down from where the attack got to
+        let mut last = level;
+        for _ in 0..20 {
+            render(&mut voice, BLOCK);
+            assert!(voice.level <= last, "{} after {}", voice.level, last);
+            last = voice.level;
         }
-        output
+        assert!(last < level);
     }
 }
@@ -7,9 +7,13 @@ use vst::{editor::Editor, plugin::PluginParameters};
 use egui_baseview::EguiWindow;
 use raw_window_handle::{HasRawWindowHandle, RawWindowHandle};
 
-use crate::plugin_state::PluginState;
+use crate::parameters::{parameter, Group, ENVELOPE_TIME, PARAMETERS, PEAK_GAIN};
+use crate::plugin_state::{Choice, PluginState, PresetInfo, NUM_LFOS, NUM_MOD_SLOTS};
 use crate::dsp::envelope::{Envelope, ADSR};
+use crate::dsp::filter::{FilterMode, StateVariableFilter};
 use crate::dsp::oscillator::{Oscillator, WaveTableOscillator};
+use crate::presets::PresetLibrary;
+use crate::units::{ChoiceText, Unit};
 
 pub struct WindowParent(pub WindowHandle);
 unsafe impl Send for WindowParent {}
@@ -62,6 +66,16 @@ unsafe impl HasRawWindowHandle for VstParent {
     }
 }
 
+// Everything the editor keeps from one frame to the next
+struct EditorState {
+    params: Arc<PluginState>,
+    presets: PresetLibrary,
+    // the text fields for saving a preset
+    save_info: PresetInfo,
+    save_tags: String,
+    save_error: Option<String>,
+}
+
 const WINDOW_WIDTH: usize = 900;
 const WINDOW_HEIGHT: usize = 1200;
 
@@ -98,13 +112,22 @@ impl Editor for PluginEditor {
             gl_config: Some(Default::default()),
         };
 
+        let save_info = self.params.preset_info();
+        let state = EditorState {
+            params: self.params.clone(),
+            presets: PresetLibrary::new(),
+            save_tags: save_info.tags.join(", "),
+            save_info,
+            save_error: None,
+        };
+
         let vst_parent = VstParent { parent };
         let window_handle = EguiWindow::open_parented(
             &vst_parent,
             settings,
-            self.params.clone(),
+            state,
             |_egui_ctx, _queue, _state| {},
-            |egui_ctx: &Context, _, state: &mut Arc<PluginState>| {
+            |egui_ctx: &Context, _, state: &mut EditorState| {
                 draw_ui(egui_ctx, state);
             },
         );
@@ -115,52 +138,228 @@ impl Editor for PluginEditor {
 }
 
 #[inline(always)]
-fn draw_ui(ctx: &Context, params: &mut Arc<PluginState>) -> egui::Response {
+fn draw_ui(ctx: &Context, state: &mut EditorState) -> egui::Response {
+    let params = state.params.clone();
     egui::CentralPanel::default()
         .show(ctx, |ui| {
             ui.vertical(|ui| {
                 ui.label("Editor");
 
+                // Preset browser
+                draw_preset_browser(ui, state);
+
                 // Sliders for volume and envelope
-                for i in 0..5 {
-                    draw_slider(ui, &params, i);
-                }
+                draw_group(ui, &params, Group::Amp);
 
-                // Draw envelope 
-                let a = params.attack.get();
-                let d = params.decay.get();
-                let s = params.sustain.get();
-                let r = params.release.get();
+                // Draw envelope
+                let a = params.global.attack.get();
+                let d = params.global.decay.get();
+                let s = params.global.sustain.get();
+                let r = params.global.release.get();
 
                 draw_envelope(ui, a, d, s, r, "env1");
-                
-                // Sliders for filter and wave warp
-                for i in 5..11 {
-                    draw_slider(ui, &params, i);
-                }
 
-                let a = params.warp_attack.get();
-                let d = params.warp_decay.get();
-                let s = params.warp_sustain.get();
-                let r = params.warp_release.get();
+                // Voices
+                draw_group(ui, &params, Group::Voices);
+
+                // Midi
+                draw_group(ui, &params, Group::Midi);
+
+                // Velocity
+                draw_group(ui, &params, Group::Velocity);
+
+                // Pitch bend range
+                draw_group(ui, &params, Group::PitchBend);
+
+                // Aftertouch
+                draw_group(ui, &params, Group::Aftertouch);
+
+                // MPE
+                draw_group(ui, &params, Group::Mpe);
+
+                // Controllers bound with midi learn
+                ui.collapsing("MIDI Mappings", |ui| {
+                    draw_midi_mappings(ui, &params);
+                });
+
+                // Sliders for filter
+                draw_group(ui, &params, Group::Filter);
+
+                // Filter response
+                draw_filter(
+                    ui,
+                    FilterMode::from_parameter(params.global.filter_mode.get()),
+                    params.global.filter_cutoff.get(),
+                    params.global.filter_resonance.get(),
+                    params.global.filter_peak_gain.get(),
+                    params.sample_rate.get(),
+                );
+
+                // Sliders for filter envelope, amount and key tracking
+                draw_group(ui, &params, Group::FilterEnvelope);
+
+                let a = params.global.filter_attack.get();
+                let d = params.global.filter_decay.get();
+                let s = params.global.filter_sustain.get();
+                let r = params.global.filter_release.get();
+
+                // Filter envelope
+                draw_envelope(ui, a, d, s, r, "env3");
+
+                // Sliders for wave warp and its envelope
+                draw_group(ui, &params, Group::Warp);
+
+                let a = params.global.warp_attack.get();
+                let d = params.global.warp_decay.get();
+                let s = params.global.warp_sustain.get();
+                let r = params.global.warp_release.get();
 
                 // Wave warp envelope
                 draw_envelope(ui, a, d, s, r, "env2");
 
-                // Warp ratio
-                draw_slider(ui, &params, 11);
-
                 // Oscillator
-                draw_oscillator(ui, params.wave_warp.get(), params.sample_rate.get());
+                draw_oscillator(ui, params.global.wave_warp.get());
+
+                // LFOs
+                for lfo in 0..NUM_LFOS {
+                    ui.collapsing(format!("LFO {}", lfo + 1), |ui| {
+                        draw_group(ui, &params, Group::Lfo(lfo));
+                    });
+                }
+
+                // Mod matrix
+                ui.collapsing("Modulation Matrix", |ui| {
+                    for slot in 0..NUM_MOD_SLOTS {
+                        draw_group(ui, &params, Group::ModSlot(slot));
+                        ui.separator();
+                    }
+                });
 
             })
         })
     .response
 }
 
+fn draw_preset_browser(ui: &mut Ui, state: &mut EditorState) {
+    // borrow the fields one by one, so the closures don't need all of the state
+    let EditorState {
+        params,
+        presets,
+        save_info,
+        save_tags,
+        save_error,
+    } = state;
+
+    ui.horizontal(|ui| {
+        if ui.button("<").clicked() {
+            presets.step(-1, params);
+        }
+        if ui.button(">").clicked() {
+            presets.step(1, params);
+        }
+
+        let info = params.preset_info();
+        let modified = if params.is_modified() { " (modified)" } else { "" };
+        ui.label(format!("{}{}", info.name, modified));
+    });
+
+    ui.collapsing("Presets", |ui| {
+        let selected = presets.category.clone().unwrap_or_else(|| "All".to_string());
+        egui::ComboBox::from_label("Category")
+            .selected_text(selected)
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut presets.category, None, "All");
+                for category in presets.categories() {
+                    let name = category.clone();
+                    ui.selectable_value(&mut presets.category, Some(category), name);
+                }
+            });
+
+        for index in presets.browsable() {
+            let preset = presets.get(index);
+            let mut text = preset.info.name.clone();
+            if preset.is_factory() {
+                text.push_str(" (factory)");
+            }
+            if !preset.info.tags.is_empty() {
+                text = format!("{}  [{}]", text, preset.info.tags.join(", "));
+            }
+            if ui
+                .selectable_label(presets.current() == Some(index), text)
+                .clicked()
+            {
+                presets.load(index, params);
+                *save_info = params.preset_info();
+                *save_tags = save_info.tags.join(", ");
+            }
+        }
+
+        ui.separator();
+
+        // save the current sound as a user preset
+        ui.horizontal(|ui| {
+            ui.label("Name");
+            ui.text_edit_singleline(&mut save_info.name);
+        });
+        ui.horizontal(|ui| {
+            ui.label("Category");
+            ui.text_edit_singleline(&mut save_info.category);
+        });
+        ui.horizontal(|ui| {
+            ui.label("Tags");
+            ui.text_edit_singleline(save_tags);
+        });
+
+        let can_save = !save_info.name.trim().is_empty();
+        if ui.add_enabled(can_save, egui::Button::new("Save")).clicked() {
+            let info = PresetInfo {
+                name: save_info.name.trim().to_string(),
+                category: save_info.category.trim().to_string(),
+                tags: save_tags
+                    .split(',')
+                    .map(|tag| tag.trim().to_string())
+                    .filter(|tag| !tag.is_empty())
+                    .collect(),
+            };
+            *save_error = presets.save(info, params).err().map(|e| e.to_string());
+        }
+        if let Some(error) = save_error {
+            ui.label(format!("Failed to save preset: {}", error));
+        }
+    });
+}
+
+// the parameters of *group*, in the order of the parameter table
+fn draw_group(ui: &mut Ui, params: &PluginState, group: Group) {
+    for (i, parameter) in PARAMETERS.iter().enumerate() {
+        if parameter.group == group {
+            draw_parameter(ui, params, i as i32);
+        }
+    }
+}
+
+// The control for a parameter depends on its entry in the parameter table
+fn draw_parameter(ui: &mut Ui, params: &PluginState, i: i32) {
+    match parameter(i).map(|parameter| parameter.unit) {
+        Some(Unit::Choice(choice)) => draw_choice(ui, params, i, choice),
+        Some(_) => draw_slider(ui, params, i),
+        None => (),
+    }
+}
+
+// Right click a slider and move a controller to bind it to the parameter.
 fn draw_slider(ui: &mut Ui, params: &PluginState, i: i32) {
     let mut val = params.get_parameter(i);
-    let parameter_name = params.get_parameter_label(i);
+    // the value is part of the text, since the value field of the slider
+    // could only be typed into as a number from 0 to 1
+    let mut parameter_name = format!("{}: {}", params.get_parameter_name(i), params.parameter_text(i));
+
+    if params.midi_learning() == Some(i) {
+        parameter_name = format!("{} (move a controller)", parameter_name);
+    } else if let Some(mapping) = params.midi_mappings.read().unwrap().mapping_for(i) {
+        parameter_name = format!("{} (CC {})", parameter_name, mapping.controller);
+    }
+
     let slider =
         ui.add(
             egui::Slider::new(&mut val, 0.0..=1.0)
@@ -171,20 +370,96 @@ fn draw_slider(ui: &mut Ui, params: &PluginState, i: i32) {
     if slider.changed() {
         params.set_parameter(i, val);
     }
+
+    if slider.secondary_clicked() {
+        params.learn_midi(i);
+    }
+}
+
+// The audio thread needs the mappings for every controller it gets,
+// so they are only locked for writing when something was changed.
+fn draw_midi_mappings(ui: &mut Ui, params: &PluginState) {
+    let mut changed = None;
+    let mut removed = None;
+
+    let mappings = params.midi_mappings.read().unwrap();
+    for mapping in mappings.mappings.iter() {
+        let mut edited = *mapping;
+        ui.horizontal(|ui| {
+            ui.label(format!(
+                "CC {} -> {}",
+                mapping.controller,
+                params.get_parameter_name(mapping.parameter)
+            ));
+            let min = ui.add(
+                egui::DragValue::new(&mut edited.min)
+                    .clamp_range(0.0..=1.0)
+                    .speed(0.01)
+                    .prefix("min ")
+            );
+            let max = ui.add(
+                egui::DragValue::new(&mut edited.max)
+                    .clamp_range(0.0..=1.0)
+                    .speed(0.01)
+                    .prefix("max ")
+            );
+            if min.changed() || max.changed() {
+                changed = Some(edited);
+            }
+            if ui.button("Remove").clicked() {
+                removed = Some(*mapping);
+            }
+        });
+    }
+    drop(mappings);
+
+    if changed.is_none() && removed.is_none() {
+        return;
+    }
+    // the audio thread might have learned a mapping in the meantime,
+    // so the mappings are found again by their controller and parameter
+    let mut mappings = params.midi_mappings.write().unwrap();
+    if let Some(edited) = changed {
+        if let Some(mapping) = mappings.mappings.iter_mut().find(|m| m.binds_same(&edited)) {
+            *mapping = edited;
+        }
+    }
+    if let Some(removed) = removed {
+        mappings.mappings.retain(|mapping| !mapping.binds_same(&removed));
+    }
+}
+
+fn draw_choice(ui: &mut Ui, params: &PluginState, i: i32, choice: ChoiceText) {
+    let current = choice.name(params.get_parameter(i));
+    let parameter_name = params.get_parameter_name(i);
+    egui::ComboBox::from_label(parameter_name)
+        .selected_text(current)
+        .show_ui(ui, |ui| {
+            for (name, value) in choice.options() {
+                if ui.selectable_label(name == current, name).clicked() {
+                    params.set_parameter(i, value);
+                }
+            }
+        });
 }
 
+// The times go up to 20 seconds, so the plot is stretched to fit the whole envelope.
+// The note is held for half as long again as the attack and decay take.
 fn draw_envelope(ui: &mut Ui, a: f32, d: f32, s: f32, r: f32, id: &str) {
     const STEP_X: f32 = 0.01;
-    const OFF_INDEX: i32 = 200;
     const TOTAL_STEPS: i32 = 300;
     const HEIGHT: f32 = 30.0;
     const WIDTH: f32 = 90.0;
 
-    let mut envelope = ADSR::new(a, d, s, r);
+    let off_time = (ENVELOPE_TIME.value(a) + ENVELOPE_TIME.value(d)) * 1.5;
+    let total_time = off_time + ENVELOPE_TIME.value(r);
+    let step_time = total_time / TOTAL_STEPS as f32;
+
+    let mut envelope = ADSR::from_parameters(a, d, s, r);
     let points: PlotPoints = (0..TOTAL_STEPS).map(|i| {
         let x = i as f32 * STEP_X;
-        let on = i <= OFF_INDEX;
-        let y = envelope.process(x, on, OFF_INDEX as f32 * STEP_X);
+        let time = i as f32 * step_time;
+        let y = envelope.process(time, time <= off_time, off_time);
 
         [x as f64, y as f64]
     }).collect();
@@ -194,17 +469,18 @@ fn draw_envelope(ui: &mut Ui, a: f32, d: f32, s: f32, r: f32, id: &str) {
 
 }
 
-fn draw_oscillator(ui: &mut Ui, wave_warp: f32, sample_rate: f32) {
+fn draw_oscillator(ui: &mut Ui, wave_warp: f32) {
     const STEP_X: f32 = 0.01;
     const TOTAL_STEPS: i32 = 300;
     const HEIGHT: f32 = 60.0;
     const WIDTH: f32 = 180.0;
     const ID: &str = "Oscillator";
 
-    let oscillator = WaveTableOscillator::new(1.0, sample_rate, wave_warp);
+    // one step of the plot is one sample of a 1 Hz wave
+    let mut oscillator = WaveTableOscillator::new(1.0, 1.0 / STEP_X, wave_warp);
     let points: PlotPoints = (0..TOTAL_STEPS).map(|i| {
         let x = i as f32 * STEP_X;
-        let y = oscillator.process(x);
+        let y = oscillator.process();
 
         [x as f64, y as f64]
 
@@ -215,6 +491,41 @@ fn draw_oscillator(ui: &mut Ui, wave_warp: f32, sample_rate: f32) {
 
 }
 
+fn draw_filter(
+    ui: &mut Ui,
+    mode: FilterMode,
+    cutoff: f32,
+    resonance: f32,
+    peak_gain: f32,
+    sample_rate: f32,
+) {
+    const TOTAL_STEPS: i32 = 300;
+    const MIN_FREQUENCY: f32 = 20.0;
+    const MIN_DB: f32 = -48.0;
+    const MAX_DB: f32 = 24.0;
+    const HEIGHT: f32 = 60.0;
+    const WIDTH: f32 = 180.0;
+    const ID: &str = "Filter";
+
+    let mut filter = StateVariableFilter::new(mode, cutoff, resonance, sample_rate);
+    filter.set_peak_gain(PEAK_GAIN.value(peak_gain));
+    let max_frequency = sample_rate / 2.0;
+
+    // frequencies are spaced logarithmically from 20 Hz to nyquist
+    let points: PlotPoints = (0..TOTAL_STEPS).map(|i| {
+        let x = i as f32 / (TOTAL_STEPS - 1) as f32;
+        let frequency = MIN_FREQUENCY * (max_frequency / MIN_FREQUENCY).powf(x);
+        let gain = 20.0 * filter.magnitude_response(frequency).log10();
+        let y = gain.max(MIN_DB).min(MAX_DB);
+
+        [x as f64, y as f64]
+    }).collect();
+
+    let line = Line::new(points);
+    draw_plot(ui, line, (HEIGHT, WIDTH), (MIN_DB, MAX_DB), (0.0, 1.0), ID);
+
+}
+
 fn draw_plot(
     ui: &mut Ui,
     line: Line,
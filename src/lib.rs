@@ -9,24 +9,31 @@ use vst::{
     buffer::AudioBuffer,
     editor::Editor,
     event::Event,
+    host::Host,
     plugin::{CanDo, Category, HostCallback, Info, Plugin, PluginParameters},
 };
 
 mod dsp;
-use dsp::PluginDsp;
+use dsp::{transport::time_info_mask, PluginDsp};
 
 mod editor;
 
 mod plugin_state;
-use plugin_state::PluginState;
+use plugin_state::{PluginState, NUM_PROGRAMS};
 
+mod midi;
 mod notes;
+mod parameters;
+use parameters::PARAMETERS;
+mod presets;
+mod units;
 use editor::PluginEditor;
 
 use log::*;
 use simplelog::*;
 
 use std::fs::File;
+use std::sync::atomic::Ordering;
 
 /// Top level wrapper that exposes a full `vst::Plugin` implementation.
 struct Synth1Vst {
@@ -50,6 +57,7 @@ impl Synth1Vst {
         let host = maybe_host.unwrap_or_default();
 
         let params = Arc::new(PluginState::default());
+        presets::load_factory_programs(&params);
 
         let editor = Some(PluginEditor {
             params: params.clone(),
@@ -86,31 +94,48 @@ impl Plugin for Synth1Vst {
             category: Category::Synth,
             inputs: 0,
             outputs: 2,
-            parameters: 12,
+            presets: NUM_PROGRAMS as i32,
+            parameters: PARAMETERS.len() as i32,
             initial_delay: 0,
             preset_chunks: true,
             ..Info::default()
         }
     }
 
+    fn set_sample_rate(&mut self, rate: f32) {
+        info!("sample rate: {}", rate);
+        self.dsp.set_sample_rate(rate);
+    }
+
+    fn set_block_size(&mut self, size: i64) {
+        self.dsp.set_block_size(size.max(0) as usize);
+    }
+
+    fn suspend(&mut self) {
+        self.dsp.reset();
+    }
+
+    fn resume(&mut self) {
+        self.dsp.reset();
+    }
+
     fn process(&mut self, buffer: &mut AudioBuffer<f32>) {
-        self.dsp.process(buffer);
+        // tempo synced modulation follows the host's transport
+        let time_info = self.host.get_time_info(time_info_mask());
+        self.dsp.process(buffer, time_info);
+
+        // program changes switch between blocks, then the host is told about them
+        self.params.apply_pending_program();
+        if self.params.program_changed.swap(false, Ordering::Relaxed) {
+            self.host.update_display();
+        }
     }
 
     fn process_events(&mut self, events: &Events) {
         for event in events.events() {
             if let Event::Midi(ev) = event {
-                match ev.data[0] {
-                    0x90 => {
-                        // note on
-                        self.params.note_on(ev);
-                    }
-                    0x80 => {
-                        // note off
-                        self.params.note_off(ev);
-                    }
-                    _ => (),
-                }
+                // the dsp applies the events at the right sample
+                self.dsp.queue_event(ev);
             }
         }
     }
@@ -118,7 +143,7 @@ impl Plugin for Synth1Vst {
     fn can_do(&self, capability: CanDo) -> Supported {
         match capability {
             CanDo::ReceiveMidiEvent => Supported::Yes,
-            //CanDo::ReceiveTimeInfo => Supported::Yes,
+            CanDo::ReceiveTimeInfo => Supported::Yes,
             _ => Supported::Maybe,
         }
     }
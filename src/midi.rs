@@ -0,0 +1,259 @@
+// Parsing of the raw midi messages the host sends us.
+
+use crate::plugin_state::Choice;
+
+use serde::{Deserialize, Serialize};
+use vst::event::MidiEvent;
+
+// controller numbers
+pub const MOD_WHEEL: u8 = 1;
+pub const SUSTAIN_PEDAL: u8 = 64;
+pub const SOSTENUTO_PEDAL: u8 = 66;
+// channel mode messages
+pub const ALL_SOUND_OFF: u8 = 120;
+pub const RESET_ALL_CONTROLLERS: u8 = 121;
+pub const ALL_NOTES_OFF: u8 = 123;
+// mpe uses the brightness controller for the "slide" dimension
+pub const SLIDE: u8 = 74;
+
+// in mpe mode, channel 1 is the manager channel for the whole zone
+// and every other channel is a member channel that plays single notes
+pub const MPE_MANAGER_CHANNEL: u8 = 0;
+
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+const POLY_PRESSURE: u8 = 0xA0;
+const CONTROL_CHANGE: u8 = 0xB0;
+const PROGRAM_CHANGE: u8 = 0xC0;
+const CHANNEL_PRESSURE: u8 = 0xD0;
+const PITCH_BEND: u8 = 0xE0;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum MidiMessage {
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    NoteOff {
+        channel: u8,
+        note: u8,
+    },
+    PolyPressure {
+        channel: u8,
+        note: u8,
+        pressure: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    ChannelPressure {
+        channel: u8,
+        pressure: u8,
+    },
+    // -1.0 is all the way down, 1.0 is all the way up
+    PitchBend {
+        channel: u8,
+        bend: f32,
+    },
+}
+
+impl MidiMessage {
+    // The lower nibble of the status byte is the channel (0-15).
+    // Returns None for the messages we don't understand.
+    pub fn from_event(e: &MidiEvent) -> Option<MidiMessage> {
+        let status = e.data[0] & 0xF0;
+        let channel = e.data[0] & 0x0F;
+
+        match status {
+            // plenty of controllers send a note on with
+            // velocity 0 instead of a note off
+            NOTE_ON if e.data[2] == 0 => Some(MidiMessage::NoteOff {
+                channel,
+                note: e.data[1],
+            }),
+            NOTE_ON => Some(MidiMessage::NoteOn {
+                channel,
+                note: e.data[1],
+                velocity: e.data[2],
+            }),
+            NOTE_OFF => Some(MidiMessage::NoteOff {
+                channel,
+                note: e.data[1],
+            }),
+            POLY_PRESSURE => Some(MidiMessage::PolyPressure {
+                channel,
+                note: e.data[1],
+                pressure: e.data[2],
+            }),
+            CONTROL_CHANGE => Some(MidiMessage::ControlChange {
+                channel,
+                controller: e.data[1],
+                value: e.data[2],
+            }),
+            PROGRAM_CHANGE => Some(MidiMessage::ProgramChange {
+                channel,
+                program: e.data[1],
+            }),
+            CHANNEL_PRESSURE => Some(MidiMessage::ChannelPressure {
+                channel,
+                pressure: e.data[1],
+            }),
+            PITCH_BEND => {
+                // 14 bits, least significant bits first. 8192 is the center.
+                let value = i32::from(e.data[1]) | (i32::from(e.data[2]) << 7);
+                let bend = (value - 8192) as f32 / if value >= 8192 { 8191.0 } else { 8192.0 };
+                Some(MidiMessage::PitchBend { channel, bend })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn channel(&self) -> u8 {
+        match *self {
+            MidiMessage::NoteOn { channel, .. } => channel,
+            MidiMessage::NoteOff { channel, .. } => channel,
+            MidiMessage::PolyPressure { channel, .. } => channel,
+            MidiMessage::ControlChange { channel, .. } => channel,
+            MidiMessage::ProgramChange { channel, .. } => channel,
+            MidiMessage::ChannelPressure { channel, .. } => channel,
+            MidiMessage::PitchBend { channel, .. } => channel,
+        }
+    }
+}
+
+// The channel(s) that we listen to
+#[derive(Clone, Copy, PartialEq)]
+pub enum ReceiveChannel {
+    Omni,
+    // 0-15, like in the status byte
+    Channel(u8),
+}
+
+const CHANNEL_NAMES: [&str; 16] = [
+    "1", "2", "3", "4", "5", "6", "7", "8", "9", "10", "11", "12", "13", "14", "15", "16",
+];
+
+impl ReceiveChannel {
+    pub fn accepts(self, channel: u8) -> bool {
+        match self {
+            ReceiveChannel::Omni => true,
+            ReceiveChannel::Channel(c) => c == channel,
+        }
+    }
+}
+
+impl Choice for ReceiveChannel {
+    const ALL: &'static [Self] = &[
+        ReceiveChannel::Omni,
+        ReceiveChannel::Channel(0),
+        ReceiveChannel::Channel(1),
+        ReceiveChannel::Channel(2),
+        ReceiveChannel::Channel(3),
+        ReceiveChannel::Channel(4),
+        ReceiveChannel::Channel(5),
+        ReceiveChannel::Channel(6),
+        ReceiveChannel::Channel(7),
+        ReceiveChannel::Channel(8),
+        ReceiveChannel::Channel(9),
+        ReceiveChannel::Channel(10),
+        ReceiveChannel::Channel(11),
+        ReceiveChannel::Channel(12),
+        ReceiveChannel::Channel(13),
+        ReceiveChannel::Channel(14),
+        ReceiveChannel::Channel(15),
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            ReceiveChannel::Omni => "Omni",
+            ReceiveChannel::Channel(c) => CHANNEL_NAMES[c as usize],
+        }
+    }
+}
+
+// How notes and expression are spread over the channels
+#[derive(Clone, Copy, PartialEq)]
+pub enum MidiMode {
+    Normal,
+    // MIDI Polyphonic Expression, with the lower zone on channels 1-16
+    Mpe,
+}
+
+impl Choice for MidiMode {
+    const ALL: &'static [Self] = &[MidiMode::Normal, MidiMode::Mpe];
+
+    fn name(self) -> &'static str {
+        match self {
+            MidiMode::Normal => "Normal",
+            MidiMode::Mpe => "MPE",
+        }
+    }
+}
+
+// Binds a midi controller to one of the parameters
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CcMapping {
+    pub controller: u8,
+    pub parameter: i32,
+    // the controller sweeps the parameter from min to max
+    pub min: f32,
+    pub max: f32,
+}
+
+impl CcMapping {
+    pub fn new(controller: u8, parameter: i32) -> Self {
+        CcMapping {
+            controller,
+            parameter,
+            min: 0.0,
+            max: 1.0,
+        }
+    }
+
+    // scale a controller value into the range of the mapping
+    pub fn parameter_value(&self, value: u8) -> f32 {
+        self.min + (self.max - self.min) * (f32::from(value) / 127.0)
+    }
+
+    // the same controller bound to the same parameter, whatever the range
+    pub fn binds_same(&self, other: &CcMapping) -> bool {
+        self.controller == other.controller && self.parameter == other.parameter
+    }
+}
+
+pub struct MidiMappings {
+    pub mappings: Vec<CcMapping>,
+}
+
+impl MidiMappings {
+    // the mod wheel sweeps the warp ratio up from the point where it has no effect
+    pub fn new(warp_ratio: i32) -> Self {
+        let mod_wheel = CcMapping {
+            min: 0.5,
+            ..CcMapping::new(MOD_WHEEL, warp_ratio)
+        };
+        MidiMappings {
+            mappings: vec![mod_wheel],
+        }
+    }
+
+    // a parameter can only be bound to one controller at a time
+    pub fn bind(&mut self, controller: u8, parameter: i32) {
+        self.mappings
+            .retain(|mapping| mapping.parameter != parameter);
+        self.mappings.push(CcMapping::new(controller, parameter));
+    }
+
+    pub fn mapping_for(&self, parameter: i32) -> Option<&CcMapping> {
+        self.mappings
+            .iter()
+            .find(|mapping| mapping.parameter == parameter)
+    }
+}
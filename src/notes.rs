@@ -1,76 +1,372 @@
 // Everything we need to keep track of notes.
 
+use crate::plugin_state::Choice;
+
 use std::collections::HashMap;
-use vst::event::MidiEvent;
 
 // Note must be public so it can be used in Voice
 #[derive(Clone, Copy)]
 pub struct Note {
+    // every note on gets a new id, so a newer note has a bigger id
+    pub id: u64,
+    // in mpe mode every note has a channel of its own,
+    // otherwise this is always 0
+    pub channel: u8,
     pub number: u8,
     pub velocity: u8,
-    pub time: f32,
-    pub off_time: f32,
-    pub on: bool,
+    // polyphonic aftertouch
+    pub pressure: u8,
+    // per note pitch bend (-1.0 to 1.0) and slide (cc 74) in mpe mode
+    pub bend: f32,
+    pub slide: u8,
+    // false once the key has been released, even if a pedal holds the note
+    pub key_down: bool,
+    // the note was held when the sostenuto pedal went down
+    pub sostenuto: bool,
 }
 
 impl Note {
-    fn from_midi(e: MidiEvent) -> Note {
-        Note {
-            number: e.data[1],
-            velocity: e.data[2],
-            time: 0.0,
-            off_time: 0.0,
-            on: true,
+    pub fn pitch(&self) -> f32 {
+        f32::from(self.number)
+    }
+}
+
+// Which of the held notes is played in mono mode
+#[derive(Clone, Copy, PartialEq)]
+pub enum NotePriority {
+    Last,
+    Low,
+    High,
+}
+
+impl Choice for NotePriority {
+    const ALL: &'static [Self] = &[NotePriority::Last, NotePriority::Low, NotePriority::High];
+
+    fn name(self) -> &'static str {
+        match self {
+            NotePriority::Last => "Last",
+            NotePriority::Low => "Low",
+            NotePriority::High => "High",
         }
     }
+}
+
+// How the velocity of a note translates into how hard it is played
+#[derive(Clone, Copy, PartialEq)]
+pub enum VelocityCurve {
+    Linear,
+    Exponential,
+    Logarithmic,
+    // every note is played at full velocity
+    Fixed,
+}
 
-    fn update_time(&mut self, time: f32) {
-        self.time += time;
+impl VelocityCurve {
+    // maps a midi velocity to 0..1
+    pub fn apply(self, velocity: u8) -> f32 {
+        let x = f32::from(velocity) / 127.0;
+        match self {
+            VelocityCurve::Linear => x,
+            VelocityCurve::Exponential => (10f32.powf(x) - 1.0) / 9.0,
+            VelocityCurve::Logarithmic => (1.0 + 9.0 * x).log10(),
+            VelocityCurve::Fixed => 1.0,
+        }
     }
+}
 
-    fn turn_off(&mut self) {
-        self.on = false;
-        self.off_time = self.time;
+impl Choice for VelocityCurve {
+    const ALL: &'static [Self] = &[
+        VelocityCurve::Linear,
+        VelocityCurve::Exponential,
+        VelocityCurve::Logarithmic,
+        VelocityCurve::Fixed,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            VelocityCurve::Linear => "Linear",
+            VelocityCurve::Exponential => "Exponential",
+            VelocityCurve::Logarithmic => "Logarithmic",
+            VelocityCurve::Fixed => "Fixed",
+        }
     }
 }
 
+// The expression of an mpe member channel is kept around,
+// since it can be sent before the note on.
+#[derive(Clone, Copy)]
+struct ChannelExpression {
+    bend: f32,
+    pressure: u8,
+    slide: u8,
+}
+
+const NEUTRAL_EXPRESSION: ChannelExpression = ChannelExpression {
+    bend: 0.0,
+    pressure: 0,
+    slide: 0,
+};
+
 // Keeps track of the notes we're supposed to be playing.
+// Notes are removed as soon as they are turned off,
+// the voices take care of the release.
+//
+// The sustain pedal keeps every released note until the pedal is lifted.
+// The sostenuto pedal only keeps the notes that were held when it went down.
 pub struct Notebook {
-    notes: HashMap<u8, Note>,
+    // notes are keyed by channel and note number
+    notes: HashMap<(u8, u8), Note>,
+    next_id: u64,
+    sustain: bool,
+    sostenuto: bool,
+    channels: [ChannelExpression; 16],
 }
 
 impl Notebook {
     pub fn new() -> Notebook {
         Notebook {
             notes: HashMap::new(),
+            next_id: 1,
+            sustain: false,
+            sostenuto: false,
+            channels: [NEUTRAL_EXPRESSION; 16],
         }
     }
 
-    pub fn note_on(&mut self, e: MidiEvent) {
-        self.notes.insert(e.data[1], Note::from_midi(e));
+    pub fn note_on(&mut self, channel: u8, number: u8, velocity: u8) {
+        let expression = self.channels[channel as usize];
+        let note = Note {
+            id: self.next_id,
+            channel,
+            number,
+            velocity,
+            pressure: expression.pressure,
+            bend: expression.bend,
+            slide: expression.slide,
+            key_down: true,
+            sostenuto: false,
+        };
+        self.notes.insert((channel, number), note);
+        self.next_id += 1;
     }
 
-    pub fn note_off(&mut self, e: MidiEvent) {
-        if let Some(note) = self.notes.get_mut(&e.data[1]) {
-            note.turn_off();
+    pub fn note_off(&mut self, channel: u8, number: u8) {
+        if let Some(note) = self.notes.get_mut(&(channel, number)) {
+            note.key_down = false;
         }
+        self.remove_released_notes();
     }
 
-    pub fn get_notes(&self) -> Vec<Note> {
-        self.notes.values().cloned().collect()
+    // Hosts send this when the transport stops or on panic, so the pedals
+    // don't get to hold on to any of the notes.
+    pub fn all_notes_off(&mut self) {
+        self.notes.clear();
     }
 
-    pub fn update_note_times(&mut self, time: f32) {
-        for note in self.notes.values_mut() {
-            note.update_time(time);
+    // lift the pedals and forget the expression of every channel
+    pub fn reset_controllers(&mut self) {
+        self.set_sostenuto(false);
+        self.set_sustain(false);
+        for channel in 0..16 {
+            self.channels[channel as usize] = NEUTRAL_EXPRESSION;
+            self.apply_channel_expression(channel);
         }
     }
 
-    // since we don't delete the note on note off events anymore
-    // we need to make sure we get rid of notes that have been off
-    // long enough that they can't make a sound anymore
-    pub fn purge_old_notes(&mut self, threshold: f32) {
+    pub fn set_sustain(&mut self, on: bool) {
+        self.sustain = on;
+        self.remove_released_notes();
+    }
+
+    pub fn set_sostenuto(&mut self, on: bool) {
+        if on && !self.sostenuto {
+            for note in self.notes.values_mut() {
+                note.sostenuto = note.key_down;
+            }
+        } else if !on {
+            for note in self.notes.values_mut() {
+                note.sostenuto = false;
+            }
+        }
+        self.sostenuto = on;
+        self.remove_released_notes();
+    }
+
+    // get rid of the notes that neither a key nor a pedal is holding
+    fn remove_released_notes(&mut self) {
+        let sustain = self.sustain;
         self.notes
-            .retain(|_, note| note.on || (note.time - note.off_time) < threshold);
+            .retain(|_, note| note.key_down || note.sostenuto || sustain);
+    }
+
+    pub fn notes(&self) -> impl Iterator<Item = &Note> {
+        self.notes.values()
+    }
+
+    pub fn poly_pressure(&mut self, channel: u8, number: u8, pressure: u8) {
+        if let Some(note) = self.notes.get_mut(&(channel, number)) {
+            note.pressure = pressure;
+        }
+    }
+
+    // Expression sent on an mpe member channel only affects the notes on that channel.
+    pub fn channel_bend(&mut self, channel: u8, bend: f32) {
+        self.channels[channel as usize].bend = bend;
+        self.apply_channel_expression(channel);
+    }
+
+    pub fn channel_pressure(&mut self, channel: u8, pressure: u8) {
+        self.channels[channel as usize].pressure = pressure;
+        self.apply_channel_expression(channel);
+    }
+
+    pub fn channel_slide(&mut self, channel: u8, slide: u8) {
+        self.channels[channel as usize].slide = slide;
+        self.apply_channel_expression(channel);
+    }
+
+    fn apply_channel_expression(&mut self, channel: u8) {
+        let expression = self.channels[channel as usize];
+        for note in self.notes.values_mut() {
+            if note.channel == channel {
+                note.bend = expression.bend;
+                note.pressure = expression.pressure;
+                note.slide = expression.slide;
+            }
+        }
+    }
+
+    pub fn find(&self, id: u64) -> Option<Note> {
+        self.notes.values().find(|note| note.id == id).cloned()
+    }
+
+    pub fn priority_note(&self, priority: NotePriority) -> Option<Note> {
+        let notes = self.notes.values();
+        match priority {
+            NotePriority::Last => notes.max_by_key(|note| note.id),
+            NotePriority::Low => notes.min_by_key(|note| note.number),
+            NotePriority::High => notes.max_by_key(|note| note.number),
+        }
+        .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the held note numbers, lowest first
+    fn held(notebook: &Notebook) -> Vec<u8> {
+        let mut notes: Vec<u8> = notebook.notes().map(|note| note.number).collect();
+        notes.sort_unstable();
+        notes
+    }
+
+    #[test]
+    fn note_off_before_the_pedal_is_not_held() {
+        let mut notebook = Notebook::new();
+        notebook.note_on(0, 60, 100);
+        notebook.note_off(0, 60);
+        notebook.set_sustain(true);
+        assert!(held(&notebook).is_empty());
+        notebook.set_sustain(false);
+        assert!(held(&notebook).is_empty());
+    }
+
+    #[test]
+    fn sustain_holds_notes_until_lifted() {
+        let mut notebook = Notebook::new();
+        notebook.note_on(0, 60, 100);
+        notebook.set_sustain(true);
+        notebook.note_off(0, 60);
+        // notes played while the pedal is down are held too
+        notebook.note_on(0, 64, 100);
+        notebook.note_off(0, 64);
+        assert_eq!(held(&notebook), vec![60, 64]);
+
+        notebook.set_sustain(false);
+        assert!(held(&notebook).is_empty());
+    }
+
+    #[test]
+    fn lifting_sustain_keeps_keys_that_are_down() {
+        let mut notebook = Notebook::new();
+        notebook.set_sustain(true);
+        notebook.note_on(0, 60, 100);
+        notebook.note_on(0, 64, 100);
+        notebook.note_off(0, 60);
+        notebook.set_sustain(false);
+        assert_eq!(held(&notebook), vec![64]);
+    }
+
+    #[test]
+    fn sostenuto_only_holds_keys_that_were_down() {
+        let mut notebook = Notebook::new();
+        notebook.note_on(0, 60, 100);
+        notebook.set_sostenuto(true);
+        notebook.note_on(0, 64, 100);
+        notebook.note_off(0, 60);
+        notebook.note_off(0, 64);
+        assert_eq!(held(&notebook), vec![60]);
+
+        notebook.set_sostenuto(false);
+        assert!(held(&notebook).is_empty());
+    }
+
+    #[test]
+    fn sostenuto_ignores_released_keys() {
+        let mut notebook = Notebook::new();
+        notebook.set_sustain(true);
+        notebook.note_on(0, 60, 100);
+        notebook.note_off(0, 60);
+        // the note is only held by the sustain pedal, so sostenuto doesn't latch it
+        notebook.set_sostenuto(true);
+        notebook.set_sustain(false);
+        assert!(held(&notebook).is_empty());
+    }
+
+    #[test]
+    fn sustain_and_sostenuto_together() {
+        let mut notebook = Notebook::new();
+        notebook.note_on(0, 60, 100);
+        notebook.set_sostenuto(true);
+        notebook.set_sustain(true);
+        notebook.note_on(0, 64, 100);
+        notebook.note_off(0, 60);
+        notebook.note_off(0, 64);
+        assert_eq!(held(&notebook), vec![60, 64]);
+
+        notebook.set_sustain(false);
+        assert_eq!(held(&notebook), vec![60]);
+
+        notebook.set_sustain(true);
+        notebook.set_sostenuto(false);
+        // the sustain pedal still holds it
+        assert_eq!(held(&notebook), vec![60]);
+
+        notebook.set_sustain(false);
+        assert!(held(&notebook).is_empty());
+    }
+
+    #[test]
+    fn striking_a_held_key_again_starts_a_new_note() {
+        let mut notebook = Notebook::new();
+        notebook.set_sustain(true);
+        notebook.note_on(0, 60, 100);
+        notebook.note_off(0, 60);
+        let first = notebook.notes().next().unwrap().id;
+
+        notebook.note_on(0, 60, 80);
+        let notes: Vec<Note> = notebook.notes().cloned().collect();
+        assert_eq!(notes.len(), 1);
+        assert!(notes[0].id > first);
+        assert!(notes[0].key_down);
+        assert_eq!(notes[0].velocity, 80);
+
+        // the key is down again, so lifting the pedal keeps the note
+        notebook.set_sustain(false);
+        assert_eq!(held(&notebook), vec![60]);
+        notebook.note_off(0, 60);
+        assert!(held(&notebook).is_empty());
     }
 }
@@ -0,0 +1,773 @@
+// The table of every parameter the host sees
+//
+// Each entry has everything there is to know about one parameter:
+// its name, range, default, unit, where the editor shows it and whether
+// hosts may automate it. The entries are declared together with the
+// structs that keep the values, further down. The VST glue in plugin_state
+// and the controls of the editor are all driven by this table, so adding
+// a parameter means adding a field to GlobalParameters (at the end, so that
+// presets keep working).
+
+use vst::util::AtomicFloat;
+
+use crate::dsp::filter::FilterMode;
+use crate::dsp::lfo::{LfoMode, LfoShape, LfoSync};
+use crate::dsp::mod_matrix::{ModDestination, ModSource};
+use crate::dsp::voice::{GlideMode, PlayMode, StealPolicy, MAX_VOICES};
+use crate::midi::{MidiMode, ReceiveChannel};
+use crate::notes::{NotePriority, VelocityCurve};
+use crate::plugin_state::{Choice, NUM_LFOS, NUM_MOD_SLOTS};
+use crate::units::{ChoiceText, Unit};
+
+// The global parameters that came before the lfos in the host's list.
+// The ones that were added since come after the mod slots, so that
+// older presets, which keep the parameters by index, still load.
+const EARLY_GLOBAL_PARAMETERS: usize = 40;
+
+#[derive(Clone, Copy)]
+pub enum Skew {
+    Linear,
+    // equal steps of the parameter multiply the value, the minimum can't be 0
+    Exponential,
+}
+
+// The values a parameter goes through as the normalized value goes from 0 to 1
+#[derive(Clone, Copy)]
+pub struct Range {
+    pub min: f32,
+    pub max: f32,
+    pub skew: Skew,
+}
+
+impl Range {
+    pub const fn linear(min: f32, max: f32) -> Self {
+        Range {
+            min,
+            max,
+            skew: Skew::Linear,
+        }
+    }
+
+    pub const fn exponential(min: f32, max: f32) -> Self {
+        Range {
+            min,
+            max,
+            skew: Skew::Exponential,
+        }
+    }
+
+    // the value for the normalized *value*
+    pub fn value(&self, value: f32) -> f32 {
+        match self.skew {
+            Skew::Linear => self.min + value * (self.max - self.min),
+            Skew::Exponential => self.min * (self.max / self.min).powf(value),
+        }
+    }
+
+    // the normalized value for *value*, which is kept in the range
+    pub fn normalize(&self, value: f32) -> f32 {
+        let value = value.max(self.min).min(self.max);
+        match self.skew {
+            Skew::Linear => (value - self.min) / (self.max - self.min),
+            Skew::Exponential => (value / self.min).ln() / (self.max / self.min).ln(),
+        }
+    }
+}
+
+// Envelope stages go from 1 ms to 20 s, so that half of the range
+// is below about 150 ms, where small changes are easy to hear
+pub const ENVELOPE_TIME: Range = Range::exponential(0.001, 20.0);
+// every octave of the filter cutoff gets the same share of the range
+pub const FILTER_CUTOFF: Range = Range::exponential(20.0, 20000.0);
+
+// Amounts are shown in percent. Bipolar amounts are 0 in the middle.
+pub const AMOUNT: Range = Range::linear(0.0, 1.0);
+pub const BIPOLAR_AMOUNT: Range = Range::linear(-1.0, 1.0);
+
+pub const POLYPHONY: Range = Range::linear(1.0, MAX_VOICES as f32);
+pub const GLIDE_TIME: Range = Range::linear(0.0, 1.0);
+// in semitones
+pub const BEND_RANGE: Range = Range::linear(0.0, 24.0);
+pub const MPE_BEND_RANGE: Range = Range::linear(0.0, 96.0);
+
+pub const LFO_RATE: Range = Range::exponential(0.05, 20.0);
+// in degrees
+pub const LFO_PHASE: Range = Range::linear(0.0, 360.0);
+pub const LFO_FADE_IN: Range = Range::linear(0.0, 5.0);
+// in semitones, in both directions
+pub const LFO_PITCH: Range = Range::linear(-12.0, 12.0);
+
+// the boost or cut of the peak filter, from -24 to +24 dB
+pub const PEAK_GAIN: Range = Range::exponential(0.0625, 16.0);
+
+// the choices, and gains that are shown in dB
+const NORMALIZED: Range = Range::linear(0.0, 1.0);
+
+// Where the editor shows a parameter
+#[derive(Clone, Copy, PartialEq)]
+pub enum Group {
+    Amp,
+    Voices,
+    Midi,
+    Velocity,
+    PitchBend,
+    Aftertouch,
+    Mpe,
+    Filter,
+    FilterEnvelope,
+    Warp,
+    Lfo(usize),
+    ModSlot(usize),
+}
+
+// Where the value of a parameter is kept in the plugin state
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Location {
+    // the index of the field in GlobalParameters
+    Global(usize),
+    // the lfo, and the index of the field in LfoParameters
+    Lfo(usize, usize),
+    ModSlot(usize, usize),
+}
+
+pub struct Parameter {
+    // stays the same even if the name changes
+    pub id: String,
+    pub name: String,
+    pub range: Range,
+    // in the unit of the parameter, not normalized
+    pub default: f32,
+    pub unit: Unit,
+    pub group: Group,
+    pub automatable: bool,
+}
+
+impl Parameter {
+    fn new(
+        id: impl Into<String>,
+        name: impl Into<String>,
+        group: Group,
+        range: Range,
+        default: f32,
+        unit: Unit,
+    ) -> Self {
+        Parameter {
+            id: id.into(),
+            name: name.into(),
+            range,
+            default,
+            unit,
+            group,
+            automatable: true,
+        }
+    }
+
+    fn choice<T: Choice>(
+        id: impl Into<String>,
+        name: impl Into<String>,
+        group: Group,
+        default: T,
+    ) -> Self {
+        Parameter::new(
+            id,
+            name,
+            group,
+            NORMALIZED,
+            default.to_parameter(),
+            Unit::Choice(ChoiceText::of::<T>()),
+        )
+    }
+
+    // for settings that hosts shouldn't automate
+    fn fixed(self) -> Self {
+        Parameter {
+            automatable: false,
+            ..self
+        }
+    }
+
+    pub fn default_value(&self) -> f32 {
+        self.range.normalize(self.default)
+    }
+
+    // the text and the unit for the normalized *value*
+    pub fn format(&self, value: f32) -> (String, &'static str) {
+        self.unit.format(self.range.value(value))
+    }
+
+    // the value and the unit in one string, e.g. "250 ms"
+    pub fn text(&self, value: f32) -> String {
+        match self.format(value) {
+            (value, "") => value,
+            (value, unit) => format!("{} {}", value, unit),
+        }
+    }
+
+    // typed text back into a normalized value
+    pub fn parse(&self, text: &str) -> Option<f32> {
+        self.unit
+            .parse(text)
+            .map(|value| self.range.normalize(value))
+    }
+}
+
+lazy_static! {
+    pub static ref PARAMETERS: Vec<Parameter> = parameters();
+}
+
+// the parameter at a host index, if there is one
+pub fn parameter(index: i32) -> Option<&'static Parameter> {
+    if index < 0 {
+        return None;
+    }
+    PARAMETERS.get(index as usize)
+}
+
+// the host index of the parameter with *id*
+pub fn index_of(id: &str) -> Option<i32> {
+    PARAMETERS
+        .iter()
+        .position(|parameter| parameter.id == id)
+        .map(|index| index as i32)
+}
+
+// Declares a struct that keeps the normalized values of some parameters,
+// together with their entries in the table. The values start out at the
+// defaults of their entries. The arguments of the struct are for entries
+// that are repeated, like the lfos.
+macro_rules! parameter_values {
+    (
+        pub struct $name:ident($($arg:ident: $arg_type:ty),*) {
+            $($field:ident: $entry:expr,)*
+        }
+    ) => {
+        pub struct $name {
+            $(pub $field: AtomicFloat,)*
+        }
+
+        impl $name {
+            pub const LEN: usize = [$(stringify!($field)),*].len();
+
+            pub fn new($($arg: $arg_type),*) -> Self {
+                let mut entries = Self::entries($($arg),*).into_iter();
+                $name {
+                    $($field: AtomicFloat::new(entries.next().unwrap().default_value()),)*
+                }
+            }
+
+            // the entries of the fields, in the same order
+            pub fn entries($($arg: $arg_type),*) -> Vec<Parameter> {
+                vec![$($entry),*]
+            }
+
+            // the value of the field at *index*
+            pub fn value(&self, index: usize) -> &AtomicFloat {
+                [$(&self.$field),*][index]
+            }
+        }
+    };
+}
+
+parameter_values! {
+    pub struct GlobalParameters() {
+        main_volume: Parameter::new(
+            "main_volume",
+            "Main Volume",
+            Group::Amp,
+            NORMALIZED,
+            0.5,
+            Unit::Decibels,
+        ),
+        attack: Parameter::new(
+            "attack",
+            "Attack",
+            Group::Amp,
+            ENVELOPE_TIME,
+            0.05,
+            Unit::Seconds,
+        ),
+        decay: Parameter::new(
+            "decay",
+            "Decay",
+            Group::Amp,
+            ENVELOPE_TIME,
+            ENVELOPE_TIME.min,
+            Unit::Seconds,
+        ),
+        sustain: Parameter::new(
+            "sustain",
+            "Sustain",
+            Group::Amp,
+            AMOUNT,
+            1.0,
+            Unit::Percent,
+        ),
+        release: Parameter::new(
+            "release",
+            "Release",
+            Group::Amp,
+            ENVELOPE_TIME,
+            0.05,
+            Unit::Seconds,
+        ),
+        filter_cutoff: Parameter::new(
+            "filter_cutoff",
+            "Filter Cutoff",
+            Group::Filter,
+            FILTER_CUTOFF,
+            20000.0,
+            Unit::Hertz,
+        ),
+        wave_warp: Parameter::new(
+            "wave_warp",
+            "Wave Warp",
+            Group::Warp,
+            AMOUNT,
+            0.5,
+            Unit::Percent,
+        ),
+        warp_attack: Parameter::new(
+            "warp_attack",
+            "Warp Attack",
+            Group::Warp,
+            ENVELOPE_TIME,
+            ENVELOPE_TIME.min,
+            Unit::Seconds,
+        ),
+        warp_decay: Parameter::new(
+            "warp_decay",
+            "Warp Decay",
+            Group::Warp,
+            ENVELOPE_TIME,
+            ENVELOPE_TIME.min,
+            Unit::Seconds,
+        ),
+        warp_sustain: Parameter::new(
+            "warp_sustain",
+            "Warp Sustain",
+            Group::Warp,
+            AMOUNT,
+            0.5,
+            Unit::Percent,
+        ),
+        warp_release: Parameter::new(
+            "warp_release",
+            "Warp Release",
+            Group::Warp,
+            ENVELOPE_TIME,
+            1.0,
+            Unit::Seconds,
+        ),
+        warp_ratio: Parameter::new(
+            "warp_ratio",
+            "Warp Ratio",
+            Group::Warp,
+            BIPOLAR_AMOUNT,
+            0.0,
+            Unit::Percent,
+        ),
+        filter_resonance: Parameter::new(
+            "filter_resonance",
+            "Filter Resonance",
+            Group::Filter,
+            AMOUNT,
+            0.0,
+            Unit::Percent,
+        ),
+        filter_mode: Parameter::choice(
+            "filter_mode",
+            "Filter Mode",
+            Group::Filter,
+            FilterMode::LowPass,
+        ),
+        filter_attack: Parameter::new(
+            "filter_attack",
+            "Filter Attack",
+            Group::FilterEnvelope,
+            ENVELOPE_TIME,
+            ENVELOPE_TIME.min,
+            Unit::Seconds,
+        ),
+        filter_decay: Parameter::new(
+            "filter_decay",
+            "Filter Decay",
+            Group::FilterEnvelope,
+            ENVELOPE_TIME,
+            ENVELOPE_TIME.min,
+            Unit::Seconds,
+        ),
+        filter_sustain: Parameter::new(
+            "filter_sustain",
+            "Filter Sustain",
+            Group::FilterEnvelope,
+            AMOUNT,
+            1.0,
+            Unit::Percent,
+        ),
+        filter_release: Parameter::new(
+            "filter_release",
+            "Filter Release",
+            Group::FilterEnvelope,
+            ENVELOPE_TIME,
+            1.0,
+            Unit::Seconds,
+        ),
+        filter_env_amount: Parameter::new(
+            "filter_env_amount",
+            "Filter Env Amount",
+            Group::FilterEnvelope,
+            BIPOLAR_AMOUNT,
+            0.0,
+            Unit::Percent,
+        ),
+        filter_key_track: Parameter::new(
+            "filter_key_track",
+            "Filter Key Track",
+            Group::FilterEnvelope,
+            AMOUNT,
+            0.0,
+            Unit::Percent,
+        ),
+        max_polyphony: Parameter::new(
+            "max_polyphony",
+            "Max Polyphony",
+            Group::Voices,
+            POLYPHONY,
+            MAX_VOICES as f32,
+            Unit::Count,
+        )
+        .fixed(),
+        steal_policy: Parameter::choice(
+            "steal_policy",
+            "Voice Stealing",
+            Group::Voices,
+            StealPolicy::ReleasedFirst,
+        ),
+        play_mode: Parameter::choice(
+            "play_mode",
+            "Play Mode",
+            Group::Voices,
+            PlayMode::Poly,
+        ),
+        note_priority: Parameter::choice(
+            "note_priority",
+            "Note Priority",
+            Group::Voices,
+            NotePriority::Last,
+        ),
+        glide_time: Parameter::new(
+            "glide_time",
+            "Glide Time",
+            Group::Voices,
+            GLIDE_TIME,
+            0.0,
+            Unit::Seconds,
+        ),
+        glide_mode: Parameter::choice(
+            "glide_mode",
+            "Glide Mode",
+            Group::Voices,
+            GlideMode::ConstantTime,
+        ),
+        midi_channel: Parameter::choice(
+            "midi_channel",
+            "MIDI Channel",
+            Group::Midi,
+            ReceiveChannel::Omni,
+        )
+        .fixed(),
+        velocity_amp: Parameter::new(
+            "velocity_amp",
+            "Velocity Amp",
+            Group::Velocity,
+            AMOUNT,
+            1.0,
+            Unit::Percent,
+        ),
+        velocity_filter: Parameter::new(
+            "velocity_filter",
+            "Velocity Filter",
+            Group::Velocity,
+            AMOUNT,
+            0.0,
+            Unit::Percent,
+        ),
+        velocity_warp: Parameter::new(
+            "velocity_warp",
+            "Velocity Warp",
+            Group::Velocity,
+            AMOUNT,
+            0.0,
+            Unit::Percent,
+        ),
+        velocity_curve: Parameter::choice(
+            "velocity_curve",
+            "Velocity Curve",
+            Group::Velocity,
+            VelocityCurve::Linear,
+        ),
+        bend_up: Parameter::new(
+            "bend_up",
+            "Bend Up",
+            Group::PitchBend,
+            BEND_RANGE,
+            2.0,
+            Unit::Semitones,
+        ),
+        bend_down: Parameter::new(
+            "bend_down",
+            "Bend Down",
+            Group::PitchBend,
+            BEND_RANGE,
+            2.0,
+            Unit::Semitones,
+        ),
+        aftertouch_volume: Parameter::new(
+            "aftertouch_volume",
+            "Aftertouch Volume",
+            Group::Aftertouch,
+            AMOUNT,
+            0.0,
+            Unit::Percent,
+        ),
+        aftertouch_cutoff: Parameter::new(
+            "aftertouch_cutoff",
+            "Aftertouch Cutoff",
+            Group::Aftertouch,
+            BIPOLAR_AMOUNT,
+            0.0,
+            Unit::Percent,
+        ),
+        aftertouch_warp: Parameter::new(
+            "aftertouch_warp",
+            "Aftertouch Warp",
+            Group::Aftertouch,
+            BIPOLAR_AMOUNT,
+            0.0,
+            Unit::Percent,
+        ),
+        midi_mode: Parameter::choice(
+            "midi_mode",
+            "MIDI Mode",
+            Group::Mpe,
+            MidiMode::Normal,
+        )
+        .fixed(),
+        // mpe controllers usually expect 48 semitones of per note bend
+        mpe_bend_range: Parameter::new(
+            "mpe_bend_range",
+            "MPE Bend Range",
+            Group::Mpe,
+            MPE_BEND_RANGE,
+            48.0,
+            Unit::Semitones,
+        )
+        .fixed(),
+        slide_cutoff: Parameter::new(
+            "slide_cutoff",
+            "Slide Cutoff",
+            Group::Mpe,
+            BIPOLAR_AMOUNT,
+            0.0,
+            Unit::Percent,
+        ),
+        slide_warp: Parameter::new(
+            "slide_warp",
+            "Slide Warp",
+            Group::Mpe,
+            BIPOLAR_AMOUNT,
+            0.0,
+            Unit::Percent,
+        ),
+        filter_peak_gain: Parameter::new(
+            "filter_peak_gain",
+            "Filter Peak Gain",
+            Group::Filter,
+            PEAK_GAIN,
+            4.0,
+            Unit::Decibels,
+        ),
+    }
+}
+
+parameter_values! {
+    pub struct LfoParameters(lfo: usize) {
+        shape: Parameter::choice("shape", "Shape", Group::Lfo(lfo), LfoShape::Sine),
+        rate: Parameter::new("rate", "Rate", Group::Lfo(lfo), LFO_RATE, 1.0, Unit::Hertz),
+        sync: Parameter::choice("sync", "Sync", Group::Lfo(lfo), LfoSync::Off),
+        mode: Parameter::choice("mode", "Mode", Group::Lfo(lfo), LfoMode::Retrigger),
+        phase: Parameter::new("phase", "Phase", Group::Lfo(lfo), LFO_PHASE, 0.0, Unit::Degrees),
+        fade_in: Parameter::new(
+            "fade_in",
+            "Fade In",
+            Group::Lfo(lfo),
+            LFO_FADE_IN,
+            0.0,
+            Unit::Seconds,
+        ),
+        // like the warp ratio, 0.5 means no modulation for all but the amp depth
+        warp_depth: Parameter::new(
+            "warp_depth",
+            "Warp Depth",
+            Group::Lfo(lfo),
+            BIPOLAR_AMOUNT,
+            0.0,
+            Unit::Percent,
+        ),
+        cutoff_depth: Parameter::new(
+            "cutoff_depth",
+            "Cutoff Depth",
+            Group::Lfo(lfo),
+            BIPOLAR_AMOUNT,
+            0.0,
+            Unit::Percent,
+        ),
+        pitch_depth: Parameter::new(
+            "pitch_depth",
+            "Pitch Depth",
+            Group::Lfo(lfo),
+            LFO_PITCH,
+            0.0,
+            Unit::Semitones,
+        ),
+        amp_depth: Parameter::new(
+            "amp_depth",
+            "Amp Depth",
+            Group::Lfo(lfo),
+            AMOUNT,
+            0.0,
+            Unit::Percent,
+        ),
+    }
+}
+
+parameter_values! {
+    pub struct ModSlotParameters(slot: usize) {
+        source: Parameter::choice("source", "Source", Group::ModSlot(slot), ModSource::None),
+        destination: Parameter::choice(
+            "destination",
+            "Destination",
+            Group::ModSlot(slot),
+            ModDestination::None,
+        ),
+        // 0.5 means no modulation
+        amount: Parameter::new(
+            "amount",
+            "Amount",
+            Group::ModSlot(slot),
+            BIPOLAR_AMOUNT,
+            0.0,
+            Unit::Percent,
+        ),
+        via: Parameter::choice("via", "Via", Group::ModSlot(slot), ModSource::None),
+    }
+}
+
+fn parameters() -> Vec<Parameter> {
+    let mut parameters = GlobalParameters::entries();
+    let later = parameters.split_off(EARLY_GLOBAL_PARAMETERS);
+
+    for lfo in 0..NUM_LFOS {
+        parameters.extend(
+            LfoParameters::entries(lfo)
+                .into_iter()
+                .map(|parameter| Parameter {
+                    id: format!("lfo{}_{}", lfo + 1, parameter.id),
+                    name: format!("LFO {} {}", lfo + 1, parameter.name),
+                    ..parameter
+                }),
+        );
+    }
+
+    for slot in 0..NUM_MOD_SLOTS {
+        parameters.extend(
+            ModSlotParameters::entries(slot)
+                .into_iter()
+                .map(|parameter| Parameter {
+                    id: format!("mod{}_{}", slot + 1, parameter.id),
+                    name: format!("Mod {} {}", slot + 1, parameter.name),
+                    ..parameter
+                }),
+        );
+    }
+
+    parameters.extend(later);
+    parameters
+}
+
+// where the value of the parameter at a host index is kept, in the same
+// order as the table
+pub fn location(index: i32) -> Option<Location> {
+    if index < 0 {
+        return None;
+    }
+    let mut index = index as usize;
+    if index < EARLY_GLOBAL_PARAMETERS {
+        return Some(Location::Global(index));
+    }
+    index -= EARLY_GLOBAL_PARAMETERS;
+
+    if index < NUM_LFOS * LfoParameters::LEN {
+        return Some(Location::Lfo(
+            index / LfoParameters::LEN,
+            index % LfoParameters::LEN,
+        ));
+    }
+    index -= NUM_LFOS * LfoParameters::LEN;
+
+    if index < NUM_MOD_SLOTS * ModSlotParameters::LEN {
+        return Some(Location::ModSlot(
+            index / ModSlotParameters::LEN,
+            index % ModSlotParameters::LEN,
+        ));
+    }
+    index -= NUM_MOD_SLOTS * ModSlotParameters::LEN;
+
+    let index = EARLY_GLOBAL_PARAMETERS + index;
+    if index < GlobalParameters::LEN {
+        Some(Location::Global(index))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_round_trip() {
+        for range in [ENVELOPE_TIME, FILTER_CUTOFF, LFO_RATE, BIPOLAR_AMOUNT] {
+            for i in 0..=10 {
+                let normalized = i as f32 / 10.0;
+                let value = range.value(normalized);
+                assert!(value >= range.min && value <= range.max * 1.0001);
+                assert!((range.normalize(value) - normalized).abs() < 1e-5);
+            }
+            // values outside of the range end up at either end
+            assert_eq!(range.normalize(range.min - 1.0), 0.0);
+            assert_eq!(range.normalize(range.max * 2.0), 1.0);
+        }
+        // every octave of an exponential range gets the same share
+        assert!((FILTER_CUTOFF.value(0.5) - 632.46).abs() < 0.01);
+    }
+
+    #[test]
+    fn every_parameter_is_kept_where_its_entry_is() {
+        let globals = GlobalParameters::entries();
+        for (index, parameter) in PARAMETERS.iter().enumerate() {
+            let id = match location(index as i32).unwrap() {
+                Location::Global(i) => globals[i].id.clone(),
+                Location::Lfo(lfo, i) => {
+                    format!("lfo{}_{}", lfo + 1, LfoParameters::entries(lfo)[i].id)
+                }
+                Location::ModSlot(slot, i) => {
+                    format!("mod{}_{}", slot + 1, ModSlotParameters::entries(slot)[i].id)
+                }
+            };
+            assert_eq!(parameter.id, id);
+        }
+        assert_eq!(location(PARAMETERS.len() as i32), None);
+        assert_eq!(location(-1), None);
+    }
+}
@@ -1,165 +1,753 @@
 // Keep track of state for synth
 
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::RwLock;
 
+use log::*;
+use serde::{Deserialize, Serialize};
 use vst::{plugin::PluginParameters, util::AtomicFloat};
 
-use vst::event::MidiEvent;
-
+use crate::midi::{
+    CcMapping, MidiMappings, MidiMode, ReceiveChannel, MOD_WHEEL, SOSTENUTO_PEDAL, SUSTAIN_PEDAL,
+};
 use crate::notes::Notebook;
+use crate::parameters::{
+    index_of, location, parameter, GlobalParameters, LfoParameters, Location, ModSlotParameters,
+    Parameter, BEND_RANGE, ENVELOPE_TIME, FILTER_CUTOFF, MPE_BEND_RANGE, PARAMETERS, POLYPHONY,
+};
 
-pub struct PluginState {
-    pub notebook: RwLock<Notebook>,
-    pub sample_rate: AtomicFloat,
+// Parameters that pick one of a few options.
+// The 0..1 range of the parameter is split evenly between the options.
+pub trait Choice: Copy + PartialEq + 'static {
+    const ALL: &'static [Self];
+
+    fn name(self) -> &'static str;
+
+    fn from_parameter(value: f32) -> Self {
+        let index = (value * (Self::ALL.len() - 1) as f32).round() as usize;
+        Self::ALL[index.min(Self::ALL.len() - 1)]
+    }
+
+    fn to_parameter(self) -> f32 {
+        let index = Self::ALL.iter().position(|option| *option == self).unwrap();
+        index as f32 / (Self::ALL.len() - 1) as f32
+    }
+}
+
+pub const NUM_LFOS: usize = 2;
 
-    // amp
-    pub main_volume: AtomicFloat,
+pub const NUM_MOD_SLOTS: usize = 8;
 
-    // adsr envelope
-    pub attack: AtomicFloat,
-    pub decay: AtomicFloat,
-    pub sustain: AtomicFloat,
-    pub release: AtomicFloat,
+// Bump this whenever the meaning of the saved data changes
+// and teach PresetData::upgrade how to read the older versions.
+//
+// 1: the parameters and the midi mappings
+// 2: envelope times and the filter cutoff are exponential, before they
+//    went linearly from 0 to 1 second and from 0 to nyquist
+const PRESET_VERSION: u32 = 2;
 
-    // simple filter
-    pub filter_cutoff: AtomicFloat,
+// the parameters that were linear times in seconds before version 2
+const ENVELOPE_TIME_PARAMETERS: [&str; 9] = [
+    "attack",
+    "decay",
+    "release",
+    "warp_attack",
+    "warp_decay",
+    "warp_release",
+    "filter_attack",
+    "filter_decay",
+    "filter_release",
+];
 
-    // wavetable oscillator
-    pub wave_warp: AtomicFloat,
+// Before version 2 the cutoff went from 0 to nyquist, but the chunk didn't
+// say at which sample rate, and hosts often restore it before telling us.
+// Old cutoffs are read as if they were saved at 48 kHz, which is what most
+// of them were made at. At 44.1 kHz they come out about 8% (1.4 semitones)
+// higher than they sounded.
+const V1_SAMPLE_RATE: f32 = 48000.0;
 
-    // wavetable oscillator envelope
-    pub warp_attack: AtomicFloat,
-    pub warp_decay: AtomicFloat,
-    pub warp_sustain: AtomicFloat,
-    pub warp_release: AtomicFloat,
+// Describes a preset in the browser
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct PresetInfo {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub category: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+// Everything that is saved in the preset chunk, and in the preset files.
+// New fields need a serde default, so that older chunks still load.
+#[derive(Clone, Serialize, Deserialize)]
+struct PresetData {
+    version: u32,
+    #[serde(flatten)]
+    info: PresetInfo,
+    // parameters are only ever added at the end, so older presets
+    // simply have fewer of them
+    parameters: Vec<f32>,
+    #[serde(default)]
+    midi_mappings: Vec<CcMapping>,
+}
 
-    pub warp_ratio: AtomicFloat,
+impl PresetData {
+    // Bring the data of an older version up to date.
+    fn upgrade(mut self) -> Self {
+        if self.version > PRESET_VERSION {
+            warn!(
+                "preset version {} is newer than {}, loading what we can",
+                self.version, PRESET_VERSION
+            );
+        }
+
+        if self.version < 2 {
+            for id in ENVELOPE_TIME_PARAMETERS.iter() {
+                if let Some(value) = self.parameter_mut(id) {
+                    *value = ENVELOPE_TIME.normalize(*value);
+                }
+            }
+            if let Some(value) = self.parameter_mut("filter_cutoff") {
+                *value = FILTER_CUTOFF.normalize(*value * V1_SAMPLE_RATE / 2.0);
+            }
+        }
+
+        PresetData {
+            version: PRESET_VERSION,
+            ..self
+        }
+    }
+
+    fn parameter_mut(&mut self, id: &str) -> Option<&mut f32> {
+        let index = index_of(id)?;
+        self.parameters.get_mut(index as usize)
+    }
+}
+
+// the host's program list
+pub const NUM_PROGRAMS: usize = 64;
+
+// there is no program change waiting to be applied
+const NO_PENDING_PROGRAM: usize = usize::MAX;
+
+// no parameter is waiting for a controller to be bound to it
+const NOT_LEARNING: usize = usize::MAX;
+
+// The bank chunk has every program in it.
+// Banks saved before there were programs are a single preset.
+#[derive(Serialize, Deserialize)]
+struct BankData {
+    version: u32,
+    current_program: usize,
+    programs: Vec<PresetData>,
+}
+
+pub struct PluginState {
+    pub notebook: RwLock<Notebook>,
+    pub midi_mappings: RwLock<MidiMappings>,
+    // The parameter that gets the next controller we see. It is kept out of
+    // the mappings, so the audio thread only has to lock them to bind it.
+    midi_learning: AtomicUsize,
+    pub sample_rate: AtomicFloat,
+    // the latest pitch bend from -1.0 to 1.0
+    pub pitch_bend: AtomicFloat,
+    // the latest channel aftertouch from 0.0 to 1.0
+    pub channel_pressure: AtomicFloat,
+    // the latest mod wheel from 0.0 to 1.0, for the mod matrix
+    pub mod_wheel: AtomicFloat,
+
+    // the values of the parameters
+    pub global: GlobalParameters,
+    pub lfos: [LfoParameters; NUM_LFOS],
+    pub mod_slots: [ModSlotParameters; NUM_MOD_SLOTS],
+
+    // the preset as it was loaded or saved, to tell if it has been modified
+    loaded_preset: RwLock<PresetData>,
+
+    // the program that is being played is kept in the parameters,
+    // its entry here is only updated when switching to another program
+    programs: RwLock<Vec<PresetData>>,
+    current_program: AtomicUsize,
+    // Program change messages arrive in the middle of a block.
+    // They wait here until the block is done.
+    pending_program: AtomicUsize,
+    // set when a program change message arrives,
+    // so the host can be told to update its display
+    pub program_changed: AtomicBool,
 }
 
 impl PluginState {
     pub fn default() -> Self {
-        Self {
+        let state = Self {
             notebook: RwLock::new(Notebook::new()),
-            // TODO update this with TimeInfo
+            // the mod wheel is bound to the warp ratio by default
+            midi_mappings: RwLock::new(MidiMappings::new(index_of("warp_ratio").unwrap())),
+            midi_learning: AtomicUsize::new(NOT_LEARNING),
+            // until the host calls set_sample_rate
             sample_rate: AtomicFloat::new(48000.0),
-            main_volume: AtomicFloat::new(0.5),
-            attack: AtomicFloat::new(0.05),
-            decay: AtomicFloat::new(0.0),
-            sustain: AtomicFloat::new(1.0),
-            release: AtomicFloat::new(0.05),
-            filter_cutoff: AtomicFloat::new(1.0),
-            wave_warp: AtomicFloat::new(0.5),
-            warp_attack: AtomicFloat::new(0.0),
-            warp_decay: AtomicFloat::new(0.0),
-            warp_sustain: AtomicFloat::new(0.5),
-            warp_release: AtomicFloat::new(1.0),
-            warp_ratio: AtomicFloat::new(0.5),
+            pitch_bend: AtomicFloat::new(0.0),
+            channel_pressure: AtomicFloat::new(0.0),
+            mod_wheel: AtomicFloat::new(0.0),
+            global: GlobalParameters::new(),
+            lfos: std::array::from_fn(LfoParameters::new),
+            mod_slots: std::array::from_fn(ModSlotParameters::new),
+            loaded_preset: RwLock::new(PresetData {
+                version: PRESET_VERSION,
+                info: PresetInfo::default(),
+                parameters: Vec::new(),
+                midi_mappings: Vec::new(),
+            }),
+            programs: RwLock::new(Vec::new()),
+            current_program: AtomicUsize::new(0),
+            pending_program: AtomicUsize::new(NO_PENDING_PROGRAM),
+            program_changed: AtomicBool::new(false),
+        };
+        state.mark_saved(PresetInfo {
+            name: "Init".to_string(),
+            ..PresetInfo::default()
+        });
+        *state.programs.write().unwrap() = vec![state.preset(); NUM_PROGRAMS];
+        state
+    }
+
+    // read a preset chunk or file
+    fn parse_preset(&self, data: &[u8]) -> Option<PresetData> {
+        match serde_json::from_slice::<PresetData>(data) {
+            Ok(preset) => Some(preset.upgrade()),
+            Err(e) => {
+                warn!("failed to load preset: {}", e);
+                None
+            }
+        }
+    }
+
+    // make *preset* the current state
+    fn apply_preset(&self, preset: PresetData) {
+        // the parameters that are newer than the preset go back to their defaults
+        for (i, (parameter, value)) in PARAMETERS.iter().zip(self.values()).enumerate() {
+            match preset.parameters.get(i) {
+                Some(saved) => value.set(*saved),
+                None => value.set(parameter.default_value()),
+            }
+        }
+        self.midi_mappings.write().unwrap().mappings = preset.midi_mappings;
+        self.mark_saved(preset.info);
+    }
+
+    fn current_program(&self) -> usize {
+        self.current_program.load(Ordering::Relaxed)
+    }
+
+    // keep the edits of the current program before switching away from it
+    fn store_current_program(&self) {
+        let preset = self.preset();
+        self.programs.write().unwrap()[self.current_program()] = preset;
+    }
+
+    // fill in one of the programs, e.g. with a factory preset
+    pub fn set_program_data(&self, program: usize, data: &[u8]) {
+        if let Some(preset) = self.parse_preset(data) {
+            if program == self.current_program() {
+                self.apply_preset(preset);
+            } else {
+                self.programs.write().unwrap()[program] = preset;
+            }
+        }
+    }
+
+    // A program change message from the midi input.
+    // This is called on the audio thread, so it only queues the program.
+    pub fn program_change(&self, program: u8) {
+        let program = usize::from(program);
+        if program < NUM_PROGRAMS {
+            self.pending_program.store(program, Ordering::Relaxed);
+            self.program_changed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // Switch to the program of the last program change message, if there was one.
+    // This runs on the audio thread after every block, so the locks are only
+    // tried. If the editor or the host has one of them, the program waits for
+    // the next block. The programs are copied into what is already there,
+    // which only allocates when a program has more in it than the last one.
+    pub fn apply_pending_program(&self) {
+        if self.pending_program.load(Ordering::Relaxed) == NO_PENDING_PROGRAM {
+            return;
+        }
+        let (mut programs, mut mappings, mut loaded) = match (
+            self.programs.try_write(),
+            self.midi_mappings.try_write(),
+            self.loaded_preset.try_write(),
+        ) {
+            (Ok(programs), Ok(mappings), Ok(loaded)) => (programs, mappings, loaded),
+            _ => return,
+        };
+        let program = self
+            .pending_program
+            .swap(NO_PENDING_PROGRAM, Ordering::Relaxed);
+        let current = self.current_program();
+        if program == NO_PENDING_PROGRAM || program == current {
+            return;
+        }
+
+        // keep the edits of the current program
+        let stored = &mut programs[current];
+        stored.version = PRESET_VERSION;
+        stored.info.clone_from(&loaded.info);
+        self.read_parameters(&mut stored.parameters);
+        stored.midi_mappings.clone_from(&mappings.mappings);
+
+        // the same as apply_preset, with the locks we already have
+        let next = &programs[program];
+        for (i, (parameter, value)) in PARAMETERS.iter().zip(self.values()).enumerate() {
+            match next.parameters.get(i) {
+                Some(saved) => value.set(*saved),
+                None => value.set(parameter.default_value()),
+            }
+        }
+        mappings.mappings.clone_from(&next.midi_mappings);
+        loaded.info.clone_from(&next.info);
+        self.read_parameters(&mut loaded.parameters);
+        loaded.midi_mappings.clone_from(&mappings.mappings);
+        self.current_program.store(program, Ordering::Relaxed);
+    }
+
+    // the values of the parameters, into the memory *parameters* already has
+    fn read_parameters(&self, parameters: &mut Vec<f32>) {
+        parameters.clear();
+        parameters.extend(self.values().map(AtomicFloat::get));
+    }
+
+    // the value of the parameter at a host index
+    pub fn value(&self, index: i32) -> Option<&AtomicFloat> {
+        Some(match location(index)? {
+            Location::Global(i) => self.global.value(i),
+            Location::Lfo(lfo, i) => self.lfos[lfo].value(i),
+            Location::ModSlot(slot, i) => self.mod_slots[slot].value(i),
+        })
+    }
+
+    // the values of all of the parameters, in the order of the table
+    fn values(&self) -> impl Iterator<Item = &AtomicFloat> {
+        (0..PARAMETERS.len() as i32).map(move |index| self.value(index).unwrap())
+    }
+
+    // the entry of the parameter at a host index, with its value
+    fn parameter_and_value(&self, index: i32) -> Option<(&'static Parameter, &AtomicFloat)> {
+        Some((parameter(index)?, self.value(index)?))
+    }
+
+    // everything that is saved, as it is right now
+    fn preset(&self) -> PresetData {
+        PresetData {
+            version: PRESET_VERSION,
+            info: self.preset_info(),
+            parameters: self.values().map(AtomicFloat::get).collect(),
+            midi_mappings: self.midi_mappings.read().unwrap().mappings.clone(),
+        }
+    }
+
+    pub fn preset_info(&self) -> PresetInfo {
+        self.loaded_preset.read().unwrap().info.clone()
+    }
+
+    // the current state as it would be saved under *info*
+    pub fn preset_data_as(&self, info: PresetInfo) -> Vec<u8> {
+        let mut preset = self.preset();
+        preset.info = info;
+        serde_json::to_vec(&preset).unwrap()
+    }
+
+    // the current state becomes the preset described by *info*
+    pub fn mark_saved(&self, info: PresetInfo) {
+        let mut preset = self.preset();
+        preset.info = info;
+        *self.loaded_preset.write().unwrap() = preset;
+    }
+
+    // whether anything has changed since the preset was loaded or saved
+    pub fn is_modified(&self) -> bool {
+        let loaded = self.loaded_preset.read().unwrap();
+        let current = self.preset();
+        loaded.parameters != current.parameters || loaded.midi_mappings != current.midi_mappings
+    }
+
+    pub fn polyphony(&self) -> usize {
+        POLYPHONY.value(self.global.max_polyphony.get()).round() as usize
+    }
+
+    // the pitch bend in semitones, with separate ranges up and down
+    pub fn pitch_bend_semitones(&self) -> f32 {
+        let bend = self.pitch_bend.get();
+        if bend >= 0.0 {
+            bend * BEND_RANGE.value(self.global.bend_up.get())
+        } else {
+            bend * BEND_RANGE.value(self.global.bend_down.get())
+        }
+    }
+
+    // the per note bend of mpe goes the same distance up and down
+    pub fn mpe_bend_semitones(&self, bend: f32) -> f32 {
+        bend * MPE_BEND_RANGE.value(self.global.mpe_bend_range.get())
+    }
+
+    // the value of a parameter with its unit, e.g. "250 ms"
+    pub fn parameter_text(&self, index: i32) -> String {
+        match self.parameter_and_value(index) {
+            Some((parameter, value)) => parameter.text(value.get()),
+            None => String::new(),
         }
     }
 
-    pub fn note_on(&self, e: MidiEvent) {
-        self.notebook.write().unwrap().note_on(e);
+    pub fn receive_channel(&self) -> ReceiveChannel {
+        ReceiveChannel::from_parameter(self.global.midi_channel.get())
     }
 
-    pub fn note_off(&self, e: MidiEvent) {
-        self.notebook.write().unwrap().note_off(e);
+    pub fn midi_mode(&self) -> MidiMode {
+        MidiMode::from_parameter(self.global.midi_mode.get())
+    }
+
+    pub fn note_on(&self, channel: u8, number: u8, velocity: u8) {
+        self.notebook
+            .write()
+            .unwrap()
+            .note_on(channel, number, velocity);
+    }
+
+    pub fn note_off(&self, channel: u8, number: u8) {
+        self.notebook.write().unwrap().note_off(channel, number);
+    }
+
+    pub fn poly_pressure(&self, channel: u8, number: u8, pressure: u8) {
+        self.notebook
+            .write()
+            .unwrap()
+            .poly_pressure(channel, number, pressure);
+    }
+
+    pub fn all_notes_off(&self) {
+        self.notebook.write().unwrap().all_notes_off();
+    }
+
+    // put the performance controllers back to where they rest,
+    // the parameters bound to controllers are left alone
+    pub fn reset_controllers(&self) {
+        self.pitch_bend.set(0.0);
+        self.channel_pressure.set(0.0);
+        self.mod_wheel.set(0.0);
+        self.notebook.write().unwrap().reset_controllers();
+    }
+
+    // expression on an mpe member channel
+    pub fn mpe_bend(&self, channel: u8, bend: f32) {
+        self.notebook.write().unwrap().channel_bend(channel, bend);
+    }
+
+    pub fn mpe_pressure(&self, channel: u8, pressure: u8) {
+        self.notebook
+            .write()
+            .unwrap()
+            .channel_pressure(channel, pressure);
+    }
+
+    pub fn mpe_slide(&self, channel: u8, slide: u8) {
+        self.notebook.write().unwrap().channel_slide(channel, slide);
+    }
+
+    pub fn set_channel_pressure(&self, pressure: u8) {
+        self.channel_pressure.set(f32::from(pressure) / 127.0);
+    }
+
+    // bind *parameter* to the next controller that is moved
+    pub fn learn_midi(&self, parameter: i32) {
+        self.midi_learning
+            .store(parameter as usize, Ordering::Relaxed);
+    }
+
+    pub fn midi_learning(&self) -> Option<i32> {
+        match self.midi_learning.load(Ordering::Relaxed) {
+            NOT_LEARNING => None,
+            parameter => Some(parameter as i32),
+        }
+    }
+
+    // This is called on the audio thread, so the mappings are only used when
+    // they are free. While the editor has them the controller is skipped,
+    // and a parameter that is learning waits for the next one.
+    pub fn control_change(&self, controller: u8, value: u8) {
+        // pedals are down from 64 up
+        let pedal_down = value >= 64;
+        match controller {
+            SUSTAIN_PEDAL => self.notebook.write().unwrap().set_sustain(pedal_down),
+            SOSTENUTO_PEDAL => self.notebook.write().unwrap().set_sostenuto(pedal_down),
+            MOD_WHEEL => self.mod_wheel.set(f32::from(value) / 127.0),
+            _ => (),
+        }
+
+        if let Some(parameter) = self.midi_learning() {
+            if let Ok(mut mappings) = self.midi_mappings.try_write() {
+                mappings.bind(controller, parameter);
+                // unless the editor started learning another parameter in the meantime
+                let _ = self.midi_learning.compare_exchange(
+                    parameter as usize,
+                    NOT_LEARNING,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                );
+            }
+        }
+
+        if let Ok(mappings) = self.midi_mappings.try_read() {
+            for mapping in mappings.mappings.iter() {
+                if mapping.controller == controller {
+                    self.set_parameter(mapping.parameter, mapping.parameter_value(value));
+                }
+            }
+        }
     }
 }
 
 impl PluginParameters for PluginState {
-    fn set_parameter(&self, index: i32, value: f32) {
-        match index {
-            0 => self.main_volume.set(value),
-            1 => self.attack.set(value),
-            2 => self.decay.set(value),
-            3 => self.sustain.set(value),
-            4 => self.release.set(value),
-            5 => self.filter_cutoff.set(value),
-            6 => self.wave_warp.set(value),
-            7 => self.warp_attack.set(value),
-            8 => self.warp_decay.set(value),
-            9 => self.warp_sustain.set(value),
-            10 => self.warp_release.set(value),
-            11 => self.warp_ratio.set(value),
+    fn get_preset_data(&self) -> Vec<u8> {
+        self.apply_pending_program();
+        serde_json::to_vec(&self.preset()).unwrap()
+    }
 
-            _ => (),
+    fn load_preset_data(&self, data: &[u8]) {
+        if let Some(preset) = self.parse_preset(data) {
+            self.apply_preset(preset);
         }
     }
 
-    fn get_parameter(&self, index: i32) -> f32 {
-        match index {
-            0 => self.main_volume.get(),
-            1 => self.attack.get(),
-            2 => self.decay.get(),
-            3 => self.sustain.get(),
-            4 => self.release.get(),
-            5 => self.filter_cutoff.get(),
-            6 => self.wave_warp.get(),
-            7 => self.warp_attack.get(),
-            8 => self.warp_decay.get(),
-            9 => self.warp_sustain.get(),
-            10 => self.warp_release.get(),
-            11 => self.warp_ratio.get(),
+    fn get_bank_data(&self) -> Vec<u8> {
+        self.apply_pending_program();
+        self.store_current_program();
+        let bank = BankData {
+            version: PRESET_VERSION,
+            current_program: self.current_program(),
+            programs: self.programs.read().unwrap().clone(),
+        };
+        serde_json::to_vec(&bank).unwrap()
+    }
+
+    fn load_bank_data(&self, data: &[u8]) {
+        let bank: BankData = match serde_json::from_slice(data) {
+            Ok(bank) => bank,
+            // an older bank, which is just the preset of the only program
+            Err(_) => {
+                self.load_preset_data(data);
+                return;
+            }
+        };
+
+        let init = PluginState::default().preset();
+        let mut programs: Vec<PresetData> = bank
+            .programs
+            .into_iter()
+            .map(PresetData::upgrade)
+            .take(NUM_PROGRAMS)
+            .collect();
+        programs.resize(NUM_PROGRAMS, init);
 
-            _ => 0.0,
+        let current = bank.current_program.min(NUM_PROGRAMS - 1);
+        let preset = programs[current].clone();
+        *self.programs.write().unwrap() = programs;
+        self.current_program.store(current, Ordering::Relaxed);
+        self.apply_preset(preset);
+    }
+
+    fn change_preset(&self, preset: i32) {
+        if preset < 0 || preset as usize >= NUM_PROGRAMS {
+            return;
         }
+        self.store_current_program();
+        self.current_program
+            .store(preset as usize, Ordering::Relaxed);
+        let preset = self.programs.read().unwrap()[preset as usize].clone();
+        self.apply_preset(preset);
     }
 
-    fn get_parameter_label(&self, index: i32) -> String {
-        match index {
-            0 => "Main Volume".to_string(),
-            1 => "Attack".to_string(),
-            2 => "Decay".to_string(),
-            3 => "Sustain".to_string(),
-            4 => "Release".to_string(),
-            5 => "Filter Cutoff".to_string(),
-            6 => "Wave Warp".to_string(),
-            7 => "Warp Attack".to_string(),
-            8 => "Warp Decay".to_string(),
-            9 => "Warp Sustain".to_string(),
-            10 => "Warp Release".to_string(),
-            11 => "Warp Ratio".to_string(),
+    // the host asks for this after we tell it to update its display
+    fn get_preset_num(&self) -> i32 {
+        self.apply_pending_program();
+        self.current_program() as i32
+    }
+
+    fn set_preset_name(&self, name: String) {
+        self.loaded_preset.write().unwrap().info.name = name;
+    }
 
-            _ => unreachable!(),
+    fn get_preset_name(&self, preset: i32) -> String {
+        self.apply_pending_program();
+        if preset < 0 || preset as usize >= NUM_PROGRAMS {
+            return String::new();
+        }
+        if preset as usize == self.current_program() {
+            self.preset_info().name
+        } else {
+            self.programs.read().unwrap()[preset as usize]
+                .info
+                .name
+                .clone()
         }
     }
 
-    fn get_parameter_text(&self, index: i32) -> String {
-        match index {
-            0 => "Main Volume".to_string(),
-            1 => "Attack".to_string(),
-            2 => "Decay".to_string(),
-            3 => "Sustain".to_string(),
-            4 => "Release".to_string(),
-            5 => "Filter Cutoff".to_string(),
-            6 => "Wave Warp".to_string(),
-            7 => "Warp Attack".to_string(),
-            8 => "Warp Decay".to_string(),
-            9 => "Warp Sustain".to_string(),
-            10 => "Warp Release".to_string(),
-            11 => "Warp Ratio".to_string(),
+    fn set_parameter(&self, index: i32, value: f32) {
+        if let Some(current) = self.value(index) {
+            current.set(value);
+        }
+    }
+
+    fn get_parameter(&self, index: i32) -> f32 {
+        match self.value(index) {
+            Some(value) => value.get(),
+            None => 0.0,
+        }
+    }
+
+    fn get_parameter_label(&self, index: i32) -> String {
+        match self.parameter_and_value(index) {
+            Some((parameter, value)) => parameter.format(value.get()).1.to_string(),
+            None => String::new(),
+        }
+    }
 
-            _ => unreachable!(),
+    fn get_parameter_text(&self, index: i32) -> String {
+        match self.parameter_and_value(index) {
+            Some((parameter, value)) => parameter.format(value.get()).0,
+            None => String::new(),
         }
     }
 
     fn get_parameter_name(&self, index: i32) -> String {
-        match index {
-            0 => "Main Volume".to_string(),
-            1 => "Attack".to_string(),
-            2 => "Decay".to_string(),
-            3 => "Sustain".to_string(),
-            4 => "Release".to_string(),
-            5 => "Filter Cutoff".to_string(),
-            6 => "Wave Warp".to_string(),
-            7 => "Warp Attack".to_string(),
-            8 => "Warp Decay".to_string(),
-            9 => "Warp Sustain".to_string(),
-            10 => "Warp Release".to_string(),
-            11 => "Warp Ratio".to_string(),
-
-            _ => unreachable!(),
-        }
-        .to_string()
+        match parameter(index) {
+            Some(parameter) => parameter.name.clone(),
+            None => String::new(),
+        }
+    }
+
+    fn can_be_automated(&self, index: i32) -> bool {
+        parameter(index).map_or(false, |parameter| parameter.automatable)
+    }
+
+    fn string_to_parameter(&self, index: i32, text: String) -> bool {
+        let (parameter, value) = match self.parameter_and_value(index) {
+            Some(parameter) => parameter,
+            None => return false,
+        };
+        match parameter.parse(&text) {
+            Some(parsed) => {
+                value.set(parsed);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starts_at_the_defaults_of_the_table() {
+        let state = PluginState::default();
+        for (i, parameter) in PARAMETERS.iter().enumerate() {
+            assert_eq!(state.get_parameter(i as i32), parameter.default_value());
+        }
+        assert_eq!(state.global.attack.get(), ENVELOPE_TIME.normalize(0.05));
+    }
+
+    #[test]
+    fn program_change_waits_until_applied() {
+        let state = PluginState::default();
+        state.global.main_volume.set(0.25);
+
+        state.program_change(3);
+        // the rest of the block still plays the old program
+        assert_eq!(state.current_program(), 0);
+        assert_eq!(state.global.main_volume.get(), 0.25);
+
+        // and the next block waits while the editor has the programs
+        let editor = state.programs.read().unwrap();
+        state.apply_pending_program();
+        assert_eq!(state.current_program(), 0);
+        drop(editor);
+
+        state.apply_pending_program();
+        assert_eq!(state.current_program(), 3);
+        assert_ne!(state.global.main_volume.get(), 0.25);
+        assert!(!state.is_modified());
+
+        // the edits of the first program were kept
+        state.program_change(0);
+        state.apply_pending_program();
+        assert_eq!(state.current_program(), 0);
+        assert_eq!(state.global.main_volume.get(), 0.25);
+    }
+
+    #[test]
+    fn learns_the_next_controller() {
+        let state = PluginState::default();
+        let cutoff = index_of("filter_cutoff").unwrap();
+        state.learn_midi(cutoff);
+        assert_eq!(state.midi_learning(), Some(cutoff));
+
+        state.control_change(20, 127);
+        assert_eq!(state.midi_learning(), None);
+        assert_eq!(state.global.filter_cutoff.get(), 1.0);
+        state.control_change(20, 0);
+        assert_eq!(state.global.filter_cutoff.get(), 0.0);
+    }
+
+    #[test]
+    fn busy_mappings_are_skipped() {
+        let state = PluginState::default();
+        let cutoff = index_of("filter_cutoff").unwrap();
+        state.learn_midi(cutoff);
+
+        let editor = state.midi_mappings.write().unwrap();
+        state.control_change(20, 127);
+        // still waiting for a controller
+        assert_eq!(state.midi_learning(), Some(cutoff));
+        drop(editor);
+
+        state.control_change(21, 127);
+        assert_eq!(state.midi_learning(), None);
+        assert_eq!(
+            state
+                .midi_mappings
+                .read()
+                .unwrap()
+                .mapping_for(cutoff)
+                .unwrap()
+                .controller,
+            21
+        );
+    }
+
+    #[test]
+    fn upgrades_version_1_times_and_cutoff() {
+        let mut preset = PluginState::default().preset();
+        preset.version = 1;
+        // half a second of attack and a cutoff half way to nyquist
+        *preset.parameter_mut("attack").unwrap() = 0.5;
+        *preset.parameter_mut("filter_release").unwrap() = 1.0;
+        *preset.parameter_mut("filter_cutoff").unwrap() = 0.5;
+        *preset.parameter_mut("main_volume").unwrap() = 0.7;
+
+        let mut preset = preset.upgrade();
+        assert_eq!(preset.version, PRESET_VERSION);
+        let mut value = |id| ENVELOPE_TIME.value(*preset.parameter_mut(id).unwrap());
+        assert!((value("attack") - 0.5).abs() < 1e-4);
+        assert!((value("filter_release") - 1.0).abs() < 1e-4);
+        let cutoff = FILTER_CUTOFF.value(*preset.parameter_mut("filter_cutoff").unwrap());
+        assert!((cutoff - 12000.0).abs() < 1.0);
+        // everything else is left alone
+        assert_eq!(*preset.parameter_mut("main_volume").unwrap(), 0.7);
+
+        // and a preset that is already up to date doesn't change
+        let again = preset.clone().upgrade();
+        assert_eq!(again.parameters, preset.parameters);
     }
 }
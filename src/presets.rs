@@ -0,0 +1,234 @@
+// The preset library
+//
+// Factory presets are built into the plugin, user presets are saved
+// as files. Both are stored just like the preset chunk of the host.
+
+use crate::plugin_state::{PluginState, PresetInfo, NUM_PROGRAMS};
+
+use log::*;
+use rust_embed::RustEmbed;
+use vst::plugin::PluginParameters;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+#[derive(RustEmbed)]
+#[folder = "presets"]
+#[include = "*.json"]
+struct FactoryPresets;
+
+const PRESET_EXTENSION: &str = "json";
+
+// Where user presets are saved.
+// We don't have a dependency for the proper platform directories,
+// so this just covers the usual places.
+pub fn user_presets_dir() -> Option<PathBuf> {
+    let base = if cfg!(target_os = "windows") {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+    };
+    base.map(|base| base.join("SynthOne").join("presets"))
+}
+
+// The factory presets are also the first programs of the host's program list
+pub fn load_factory_programs(params: &PluginState) {
+    for (program, path) in FactoryPresets::iter().enumerate().take(NUM_PROGRAMS) {
+        if let Some(file) = FactoryPresets::get(&path) {
+            params.set_program_data(program, &file.data);
+        }
+    }
+}
+
+#[derive(Clone)]
+enum PresetLocation {
+    // the path within the embedded folder
+    Factory(String),
+    User(PathBuf),
+}
+
+pub struct PresetEntry {
+    pub info: PresetInfo,
+    location: PresetLocation,
+}
+
+impl PresetEntry {
+    pub fn is_factory(&self) -> bool {
+        matches!(self.location, PresetLocation::Factory(_))
+    }
+
+    fn data(&self) -> Option<Vec<u8>> {
+        match &self.location {
+            PresetLocation::Factory(path) => {
+                FactoryPresets::get(path).map(|file| file.data.into_owned())
+            }
+            PresetLocation::User(path) => match fs::read(path) {
+                Ok(data) => Some(data),
+                Err(e) => {
+                    warn!("failed to read preset {}: {}", path.display(), e);
+                    None
+                }
+            },
+        }
+    }
+}
+
+pub struct PresetLibrary {
+    presets: Vec<PresetEntry>,
+    // the preset that was loaded last
+    current: Option<usize>,
+    // only presets in this category are browsed, or all of them if None
+    pub category: Option<String>,
+}
+
+impl PresetLibrary {
+    pub fn new() -> Self {
+        let mut library = PresetLibrary {
+            presets: Vec::new(),
+            current: None,
+            category: None,
+        };
+        library.scan();
+        library
+    }
+
+    // Look for presets again, e.g. after saving one.
+    // The presets are sorted by category and then by name.
+    pub fn scan(&mut self) {
+        self.presets.clear();
+        self.current = None;
+
+        for path in FactoryPresets::iter() {
+            if let Some(file) = FactoryPresets::get(&path) {
+                let location = PresetLocation::Factory(path.to_string());
+                self.add(&file.data, location);
+            }
+        }
+
+        if let Some(dir) = user_presets_dir() {
+            // there is no directory until the first preset is saved
+            if let Ok(entries) = fs::read_dir(&dir) {
+                for entry in entries.flatten() {
+                    let path = entry.path();
+                    if path
+                        .extension()
+                        .map_or(false, |ext| ext == PRESET_EXTENSION)
+                    {
+                        if let Ok(data) = fs::read(&path) {
+                            self.add(&data, PresetLocation::User(path));
+                        }
+                    }
+                }
+            }
+        }
+
+        self.presets.sort_by(|a, b| {
+            (&a.info.category, &a.info.name).cmp(&(&b.info.category, &b.info.name))
+        });
+    }
+
+    fn add(&mut self, data: &[u8], location: PresetLocation) {
+        // the info is part of the preset data, the rest is skipped
+        match serde_json::from_slice::<PresetInfo>(data) {
+            Ok(info) => self.presets.push(PresetEntry { info, location }),
+            Err(e) => warn!("skipping broken preset: {}", e),
+        }
+    }
+
+    pub fn categories(&self) -> Vec<String> {
+        let mut categories: Vec<String> = self
+            .presets
+            .iter()
+            .map(|preset| preset.info.category.clone())
+            .filter(|category| !category.is_empty())
+            .collect();
+        categories.sort();
+        categories.dedup();
+        categories
+    }
+
+    // the indices of the presets in the selected category
+    pub fn browsable(&self) -> Vec<usize> {
+        (0..self.presets.len())
+            .filter(|i| match &self.category {
+                Some(category) => self.presets[*i].info.category == *category,
+                None => true,
+            })
+            .collect()
+    }
+
+    pub fn get(&self, index: usize) -> &PresetEntry {
+        &self.presets[index]
+    }
+
+    pub fn current(&self) -> Option<usize> {
+        self.current
+    }
+
+    pub fn load(&mut self, index: usize, params: &PluginState) {
+        if let Some(data) = self.presets[index].data() {
+            params.load_preset_data(&data);
+            self.current = Some(index);
+        }
+    }
+
+    // load the previous (-1) or next (1) preset in the selected category
+    pub fn step(&mut self, offset: isize, params: &PluginState) {
+        let browsable = self.browsable();
+        if browsable.is_empty() {
+            return;
+        }
+
+        let position = self
+            .current
+            .and_then(|current| browsable.iter().position(|i| *i == current));
+        let next = match position {
+            Some(position) => {
+                (position as isize + offset).rem_euclid(browsable.len() as isize) as usize
+            }
+            None => 0,
+        };
+        self.load(browsable[next], params);
+    }
+
+    // Save the current state as a user preset.
+    // A user preset with the same name is overwritten.
+    pub fn save(&mut self, info: PresetInfo, params: &PluginState) -> io::Result<()> {
+        let dir = user_presets_dir()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no presets directory"))?;
+        fs::create_dir_all(&dir)?;
+
+        // keep the file name safe on every platform
+        let file_name: String = info
+            .name
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || c == ' ' || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let path = dir.join(file_name).with_extension(PRESET_EXTENSION);
+
+        // the state only counts as saved once it is on disk
+        fs::write(&path, params.preset_data_as(info.clone()))?;
+        params.mark_saved(info);
+        info!("saved preset {}", path.display());
+
+        self.scan();
+        self.current = self
+            .presets
+            .iter()
+            .position(|preset| match &preset.location {
+                PresetLocation::User(user_path) => *user_path == path,
+                PresetLocation::Factory(_) => false,
+            });
+        Ok(())
+    }
+}
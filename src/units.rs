@@ -0,0 +1,178 @@
+// How the values of the parameters are shown
+//
+// The text is split into the value and the unit, since that is
+// how VST hosts ask for it. The editor shows the two together.
+// The values here are in real units, the range of each parameter
+// turns them into the normalized 0..1 value and back.
+
+use crate::plugin_state::Choice;
+
+// Parameters that pick an option are shown by the name of the option
+#[derive(Clone, Copy)]
+pub struct ChoiceText {
+    name: fn(f32) -> &'static str,
+    // every option with its normalized value
+    options: fn() -> Vec<(&'static str, f32)>,
+}
+
+impl ChoiceText {
+    pub fn of<T: Choice>() -> Self {
+        ChoiceText {
+            name: |value| T::from_parameter(value).name(),
+            options: || {
+                T::ALL
+                    .iter()
+                    .map(|option| (option.name(), option.to_parameter()))
+                    .collect()
+            },
+        }
+    }
+
+    pub fn name(&self, value: f32) -> &'static str {
+        (self.name)(value)
+    }
+
+    pub fn options(&self) -> Vec<(&'static str, f32)> {
+        (self.options)()
+    }
+
+    fn parse(&self, text: &str) -> Option<f32> {
+        self.options()
+            .into_iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(text))
+            .map(|(_, value)| value)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Unit {
+    Seconds,
+    Hertz,
+    // a gain, shown in dB
+    Decibels,
+    // a fraction, shown in percent
+    Percent,
+    Semitones,
+    Degrees,
+    // whole numbers
+    Count,
+    Choice(ChoiceText),
+}
+
+impl Unit {
+    // the value and the unit for *value*
+    pub fn format(self, value: f32) -> (String, &'static str) {
+        match self {
+            Unit::Seconds => format_seconds(value),
+            Unit::Hertz => format_hertz(value),
+            Unit::Decibels => {
+                if value <= 0.0 {
+                    ("-inf".to_string(), "dB")
+                } else {
+                    (format!("{:.1}", 20.0 * value.log10()), "dB")
+                }
+            }
+            Unit::Percent => (format!("{:.0}", value * 100.0), "%"),
+            Unit::Semitones => (format!("{:.1}", value), "st"),
+            Unit::Degrees => (format!("{:.0}", value), "deg"),
+            Unit::Count => (format!("{:.0}", value), ""),
+            Unit::Choice(choice) => (choice.name(value).to_string(), ""),
+        }
+    }
+
+    // Turn typed text back into a value.
+    // The unit can be left out, then the number is taken to be in the base unit
+    // (seconds, Hz, dB, percent or semitones).
+    pub fn parse(self, text: &str) -> Option<f32> {
+        let text = text.trim();
+        if let Unit::Choice(choice) = self {
+            return choice.parse(text);
+        }
+        if let Unit::Decibels = self {
+            if text.to_ascii_lowercase().starts_with("-inf") {
+                return Some(0.0);
+            }
+        }
+
+        let (number, unit) = split_number(text)?;
+        let unit = unit.to_ascii_lowercase();
+        // a unit that doesn't belong to the parameter isn't taken
+        let value = match (self, unit.as_str()) {
+            (Unit::Seconds, "ms") => number / 1000.0,
+            (Unit::Seconds, "" | "s") => number,
+            (Unit::Hertz, unit) => hertz(number, unit)?,
+            (Unit::Decibels, "" | "db") => 10.0_f32.powf(number / 20.0),
+            (Unit::Percent, "" | "%") => number / 100.0,
+            (Unit::Semitones, "" | "st") | (Unit::Degrees, "" | "deg") | (Unit::Count, "") => {
+                number
+            }
+            _ => return None,
+        };
+        Some(value)
+    }
+}
+
+// short times in ms, the rest in seconds
+fn format_seconds(seconds: f32) -> (String, &'static str) {
+    // anything that would round up to 1000 ms is shown as 1.00 s
+    if seconds < 0.9995 {
+        (format!("{:.0}", seconds * 1000.0), "ms")
+    } else {
+        (format!("{:.2}", seconds), "s")
+    }
+}
+
+fn format_hertz(frequency: f32) -> (String, &'static str) {
+    if frequency >= 999.5 {
+        (format!("{:.2}", frequency / 1000.0), "kHz")
+    } else if frequency >= 10.0 {
+        (format!("{:.0}", frequency), "Hz")
+    } else {
+        (format!("{:.2}", frequency), "Hz")
+    }
+}
+
+fn hertz(number: f32, unit: &str) -> Option<f32> {
+    match unit {
+        "khz" | "k" => Some(number * 1000.0),
+        "" | "hz" => Some(number),
+        _ => None,
+    }
+}
+
+// "250ms" or "250 ms" is (250.0, "ms")
+fn split_number(text: &str) -> Option<(f32, &str)> {
+    let end = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+'))
+        .unwrap_or(text.len());
+    let number = text[..end].parse().ok()?;
+    Some((number, text[end..].trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_with_or_without_the_unit() {
+        assert_eq!(Unit::Seconds.parse("250 ms"), Some(0.25));
+        assert_eq!(Unit::Seconds.parse("2"), Some(2.0));
+        assert_eq!(Unit::Hertz.parse("2 kHz"), Some(2000.0));
+        assert_eq!(Unit::Decibels.parse("0 dB"), Some(1.0));
+        assert_eq!(Unit::Decibels.parse("-inf"), Some(0.0));
+        assert_eq!(Unit::Percent.parse("50%"), Some(0.5));
+        assert_eq!(Unit::Semitones.parse("-7 st"), Some(-7.0));
+        assert_eq!(Unit::Degrees.parse("90"), Some(90.0));
+    }
+
+    #[test]
+    fn rejects_the_unit_of_another_parameter() {
+        assert_eq!(Unit::Seconds.parse("2 kHz"), None);
+        assert_eq!(Unit::Hertz.parse("250 ms"), None);
+        assert_eq!(Unit::Decibels.parse("2 kHz"), None);
+        assert_eq!(Unit::Percent.parse("3 dB"), None);
+        assert_eq!(Unit::Semitones.parse("90 deg"), None);
+        assert_eq!(Unit::Degrees.parse("12 st"), None);
+        assert_eq!(Unit::Count.parse("8%"), None);
+    }
+}